@@ -0,0 +1,102 @@
+// Interpreter throughput: runs a few programs over and over and reports how
+// many instructions a second the vm gets through. No dependencies, so just
+//
+//   cargo bench --bench vm
+//
+// Only running is timed, not setting up the states to run. Numbers vary
+// between machines; compare runs on the same one. Each program also gets a
+// "decoded" row that checks and decodes every instruction as it runs it, the
+// way the vm did before programs were decoded up front, to compare against.
+use calc::helium::asm::assemble;
+use calc::vm::compact::decode;
+use calc::{Instruction, State, Status, Target};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// How long each program is run for
+const BUDGET: Duration = Duration::from_secs(2);
+// States set up at a time
+const BATCH: usize = 100;
+
+// Register arithmetic, the kind of code compile makes with enough registers
+const REGISTERS: &str = "\
+Ldc Reg0, {n}
+Ldc Reg1, 3
+Add Reg0, Reg1
+Mul Reg1, Reg0
+Copy Reg2, Reg1
+Sub Reg2, 7
+Div Reg1, 3
+Out Reg2
+";
+
+// The same through stack slots, the kind it makes when it runs out
+const STACK: &str = "\
+Push {n}
+Push 3
+Add Stack(0), Stack(1)
+Mul Stack(1), Stack(0)
+Copy Stack(0), Stack(1)
+Div Stack(0), 2
+Pop Reg0
+Pop Reg1
+Out Reg1
+";
+
+// block repeated for n in 0..200, then Halt
+fn program(block: &str) -> Vec<Instruction> {
+    let text: String = (0..200).map(|n| block.replace("{n}", &n.to_string())).collect();
+    assemble(&(text + "Halt\n")).unwrap().instructions
+}
+
+// Runs state to the end
+fn run(state: &mut State, _: &[Instruction], _: &Target) -> Status {
+    state.advance(usize::MAX)
+}
+
+// The same, decoding each instruction before it runs
+fn run_decoding(state: &mut State, insns: &[Instruction], target: &Target) -> Status {
+    loop {
+        black_box(decode(&insns[state.index()], target).unwrap());
+        match state.advance(1) {
+            Status::Running => {}
+            status => return status,
+        }
+    }
+}
+
+type Runner = fn(&mut State, &[Instruction], &Target) -> Status;
+
+fn bench(name: &str, block: &str, runner: Runner) {
+    let target = Target::default();
+    let insns = program(block);
+    let mut timed = Duration::ZERO;
+    let mut runs: u64 = 0;
+
+    while timed < BUDGET {
+        let mut states: Vec<State> = (0..BATCH).map(|_| State::new(&target, insns.clone())).collect();
+
+        let start = Instant::now();
+        for state in states.iter_mut() {
+            assert!(matches!(black_box(runner(state, &insns, &target)), Status::Finished(_)));
+        }
+        timed += start.elapsed();
+        runs += BATCH as u64;
+    }
+
+    let seconds = timed.as_secs_f64();
+    println!(
+        "{:<10} {:>5} insns  {:>9.0} runs/s  {:>7.1} M insns/s",
+        name,
+        insns.len(),
+        runs as f64 / seconds,
+        (runs * insns.len() as u64) as f64 / seconds / 1e6
+    );
+}
+
+fn main() {
+    bench("registers", REGISTERS, run);
+    bench("decoded", REGISTERS, run_decoding);
+    bench("stack", STACK, run);
+    bench("decoded", STACK, run_decoding);
+}
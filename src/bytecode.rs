@@ -1,289 +1,281 @@
-use std::fmt::{Display, Formatter};
-use crate::bytecode::InsnOperand::{Reg0, Reg1, Reg2, Reg3, Reg4, Reg5, Reg6, Reg7};
+use crate::error::Error;
+use crate::helium::{InsnOpcode, InsnOperand, Instruction, Target, HELIUM};
 use crate::token::{Opcode, Operand, Token};
 use crate::tree::Node;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum InsnOpcode {
-    // data
-    Ldc,
-    Push,
-    Pop,
-    Copy,
-
-    // math
-    Add,
-    Sub,
-    Mul,
-    Div,
-}
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum InsnOperand {
-    Imm(i32),
-    Stack(usize),
-    Reg0,
-    Reg1,
-    Reg2,
-    Reg3,
-    Reg4,
-    Reg5,
-    Reg6,
-    Reg7,
-}
-#[derive(Debug, Clone)]
-pub struct Instruction {
-    pub opcode: InsnOpcode,
-    pub operands: Vec<InsnOperand>,
-}
-fn operand_to_insn_operand(opcode: Operand) -> Result<InsnOperand, &'static str> {
+fn operand_to_insn_operand(opcode: Operand) -> Result<InsnOperand, Error> {
     match opcode {
         Operand::Int(x) => Ok(InsnOperand::Imm(x)),
-        _ => Err("Invalid operand"),
+        _ => Err(Error::compile("Invalid operand", None)),
     }
 }
-fn operand_to_insn_opcode(opcode: Operand) -> Result<InsnOpcode, &'static str> {
+fn operand_to_insn_opcode(opcode: Operand) -> Result<InsnOpcode, Error> {
     match opcode {
         Operand::Add => Ok(InsnOpcode::Add),
         Operand::Sub => Ok(InsnOpcode::Sub),
         Operand::Mul => Ok(InsnOpcode::Mul),
         Operand::Div => Ok(InsnOpcode::Div),
-        _ => Err("Invalid operand"),
+        _ => Err(Error::compile("Invalid operand", None)),
+    }
+}
+// Sethi-Ullman number: how many registers evaluating this node needs without spilling
+pub fn registers_needed(node: &Node<Token>) -> usize {
+    match &node.value {
+        None => match &node.left {
+            None => 0,
+            Some(left) => registers_needed(left),
+        },
+        Some(token) => match token.op {
+            Opcode::Const | Opcode::Separator => 1,
+            Opcode::Operand => {
+                let left = node.left.as_ref().map_or(0, |left| registers_needed(left));
+                // a constant on the right is used as an immediate, without a register
+                let right = match &node.right {
+                    Some(right) if right.constant().is_none() => registers_needed(right),
+                    _ => 0,
+                };
+                // same for one on the left when the operands can be swapped
+                if matches!(token.or, Operand::Add | Operand::Mul)
+                    && node.left.as_ref().is_some_and(|left| left.constant().is_some())
+                {
+                    return right.max(1);
+                }
+
+                if left == right {
+                    left + 1
+                } else {
+                    left.max(right)
+                }
+            }
+        },
     }
 }
+
 fn handle_node(
     reg_alloc: &mut RegisterAllocation,
+    insns: &mut Vec<Instruction>,
     operand: Operand,
-    left_reg: InsnOperand,
-    right_reg: InsnOperand,
-    node: Node<Token>,
-) -> Result<Vec<Instruction>, &'static str> {
-    let mut insns: Vec<Instruction> = Vec::new();
-    let mut recursed = false;
-    // the next register that is going to be used
-    let mut next_reg = InsnOperand::Imm(-1);
-    // the last register used inside node_to_instructions
-    let mut recursed_last_reg = InsnOperand::Imm(-1);
-
-    match &node.left {
-        None => return Err("No left hand side value for instruction"),
-        Some(left_node) => match &left_node.value {
-            None => return Err("No value in left hand side of instruction"),
-            Some(left) => match left.op {
-                Opcode::Const => match operand_to_insn_operand(left.or) {
-                    Ok(val) => {
-                        insns.push(Instruction {
-                            opcode: InsnOpcode::Ldc,
-                            operands: vec![left_reg, val],
-                        });
-                    }
-                    Err(err) => return Err(err),
-                },
-                Opcode::Operand => match node_to_instructions(reg_alloc, (**left_node).clone()) {
-                    Err(err) => return Err(err),
-                    Ok(insns2) => {
-                        println!("recursed insns: {:?}", insns2);
-                        for insn in insns2 {
-                            match insn.clone().operands.get(0) {
-                                None => {}
-                                Some(reg) => recursed_last_reg = reg.clone(),
-                            }
-                            insns.push(insn);
-                        }
-                        recursed = true;
-                        next_reg = left_reg;
-                    }
-                },
-            },
-        },
+    node: &Node<Token>,
+) -> Result<InsnOperand, Error> {
+    let left_node = match &node.left {
+        None => return Err(Error::compile("No left hand side value for instruction", node.span())),
+        Some(left_node) => left_node,
+    };
+    let right_node = match &node.right {
+        None => return Err(Error::compile("No right hand side value for instruction", node.span())),
+        Some(right_node) => right_node,
+    };
+    if left_node.value.is_none() {
+        return Err(Error::compile("No value in left hand side of instruction", node.span()));
+    }
+    if right_node.value.is_none() {
+        return Err(Error::compile("No value in right hand side of instruction", node.span()));
     }
 
-    if recursed {
+    // a constant operand doesn't need a register, it's used as an immediate
+    let mut immediate = right_node.constant().map(|x| (left_node, x));
+    if immediate.is_none() && matches!(operand, Operand::Add | Operand::Mul) {
+        immediate = left_node.constant().map(|x| (right_node, x));
+    }
+    if let Some((other, x)) = immediate {
+        let reg = node_to_instructions(reg_alloc, insns, other)?;
         insns.push(Instruction {
-            opcode: InsnOpcode::Copy,
-            operands: vec![next_reg, recursed_last_reg],
+            opcode: operand_to_insn_opcode(operand)?,
+            operands: vec![reg, InsnOperand::Imm(x)],
+            span: node.span(),
         });
+
+        return Ok(reg);
     }
 
-    match handle_right(reg_alloc, operand, left_reg, right_reg, node) {
-        Err(err) => return Err(err),
-        Ok(insns2) => {
-            for insn in insns2 {
-                insns.push(insn);
-            }
-        }
+    // evaluate the side that needs more registers first, so its result only
+    // ties up one register while the other side is computed
+    let left_reg;
+    let right_reg;
+    if registers_needed(right_node) > registers_needed(left_node) {
+        right_reg = node_to_instructions(reg_alloc, insns, right_node)?;
+        left_reg = node_to_instructions(reg_alloc, insns, left_node)?;
+    } else {
+        left_reg = node_to_instructions(reg_alloc, insns, left_node)?;
+        right_reg = node_to_instructions(reg_alloc, insns, right_node)?;
     }
 
-    Ok(insns)
+    insns.push(Instruction {
+        opcode: operand_to_insn_opcode(operand)?,
+        operands: vec![left_reg, right_reg],
+        span: node.span(),
+    });
+    reg_alloc.free(right_reg);
+
+    Ok(left_reg)
 }
 
-fn handle_right(
+// Emits the instructions for node and returns where its value ends up
+fn node_to_instructions(
     reg_alloc: &mut RegisterAllocation,
-    operand: Operand,
-    left_reg: InsnOperand,
-    right_reg: InsnOperand,
-    node: Node<Token>,
-) -> Result<Vec<Instruction>, &'static str> {
-    let mut insns: Vec<Instruction> = Vec::new();
-
-    match &node.right {
-        None => return Err("No right hand side value for instruction"),
-        Some(right_node) => match &right_node.value {
-            None => return Err("No value in right hand side of instruction"),
-            Some(right) => match right.op {
-                Opcode::Const => match operand_to_insn_operand(right.or) {
-                    Ok(val) => {
-                        insns.push(Instruction {
-                            opcode: InsnOpcode::Ldc,
-                            operands: vec![right_reg, val],
-                        });
-
-                        println!("{:?}", &node);
-                        match operand_to_insn_opcode(operand) {
-                            Err(err) => return Err(err),
-                            Ok(op) => insns.push(Instruction {
-                                opcode: op,
-                                operands: vec![left_reg, right_reg],
-                            }),
-                        }
-                    }
-                    Err(err) => return Err(err),
-                },
-
-                Opcode::Operand => {
-                    let i = (**right_node).clone();
-                    match node_to_instructions(reg_alloc, i) {
-                        Err(err) => return Err(err),
-                        Ok(insns2) => {
-                            for insn in insns2 {
-                                insns.push(insn);
-                            }
-                        }
-                    }
-
-                    match insns.get(insns.len() - 1) {
-                        None => return Err("node_to_instructions didn't emit any instructions!"),
-                        Some(last_insn) => match last_insn.operands.get(0) {
-                            None => return Err("last_insn doesn't have any operands"),
-                            Some(operand) => insns.push(Instruction {
-                                opcode: InsnOpcode::Copy,
-                                operands: vec![right_reg, operand.clone()],
-                            }),
-                        },
-                    }
+    insns: &mut Vec<Instruction>,
+    node: &Node<Token>,
+) -> Result<InsnOperand, Error> {
+    match &node.value {
+        None => match &node.left {
+            None => Err(Error::compile("Empty expression", node.span())),
+            Some(left) => node_to_instructions(reg_alloc, insns, left),
+        },
+        Some(token) => match token.op {
+            Opcode::Const => {
+                let reg = reg_alloc.alloc();
+                insns.push(Instruction {
+                    opcode: InsnOpcode::Ldc,
+                    operands: vec![reg, operand_to_insn_operand(token.or)?],
+                    span: Some(token.span),
+                });
 
-                    match operand_to_insn_opcode(operand) {
-                        Err(err) => return Err(err),
-                        Ok(op) => {
-                            insns.push(Instruction {
-                                opcode: op,
-                                operands: vec![left_reg, right_reg],
-                            });
-                        }
-                    }
-                }
-            },
+                Ok(reg)
+            }
+            Opcode::Operand => handle_node(reg_alloc, insns, token.or, node),
+            Opcode::Separator => Err(Error::compile("Unexpected separator", node.span())),
         },
     }
-
-    Ok(insns)
 }
 
-fn node_to_instructions(
-    reg_alloc: &mut RegisterAllocation,
-    node: Node<Token>,
-) -> Result<Vec<Instruction>, &'static str> {
-    let mut insns: Vec<Instruction> = Vec::new();
+// Over the registers of a target that passed Target::check
+pub struct RegisterAllocation {
+    registers: Vec<InsnOperand>,
+    // which of the registers above currently hold a live value
+    used: Vec<bool>,
+    // same for the stack slots values get spilled to
+    stack: Vec<bool>,
+}
 
-    match &node.value {
-        None => {
-            return Ok(insns);
+impl RegisterAllocation {
+    pub fn new(target: &Target) -> RegisterAllocation {
+        let registers: Vec<InsnOperand> = (0..target.registers).map(|n| InsnOperand::Reg(n as u8)).collect();
+        RegisterAllocation {
+            used: vec![false; registers.len()],
+            registers,
+            stack: Vec::new(),
         }
-        Some(token) => match token.or {
-            Operand::Add | Operand::Sub | Operand::Mul | Operand::Div => {
-                let reg1;
-                let reg2;
+    }
 
-                if reg_alloc.registers.len() > 1 {
-                    reg1 = reg_alloc.registers.swap_remove(0);
-                    reg2 = reg_alloc.registers.swap_remove(0);
-                } else if reg_alloc.registers.len() > 0 {
-                    reg1 = reg_alloc.registers.swap_remove(0);
-                    reg2 = InsnOperand::Stack(reg_alloc.stack_index);
-                    reg_alloc.stack_index += 1;
-                } else {
-                    reg1 = InsnOperand::Stack(reg_alloc.stack_index);
-                    reg_alloc.stack_index += 1;
-                    reg2 = InsnOperand::Stack(reg_alloc.stack_index);
-                    reg_alloc.stack_index += 1;
-                }
+    // Hands out the lowest free register, or a stack slot once all of them are taken
+    pub fn alloc(&mut self) -> InsnOperand {
+        if let Some(i) = self.used.iter().position(|used| !used) {
+            self.used[i] = true;
+            return self.registers[i];
+        }
 
-                match handle_node(reg_alloc, token.or, reg1, reg2, node) {
-                    Ok(insns2) => {
-                        for insn in insns2 {
-                            insns.push(insn);
-                        }
-                    }
-                    Err(err) => return Err(err),
-                }
+        match self.stack.iter().position(|used| !used) {
+            Some(i) => {
+                self.stack[i] = true;
+                InsnOperand::Stack(i)
             }
+            None => {
+                self.stack.push(true);
+                InsnOperand::Stack(self.stack.len() - 1)
+            }
+        }
+    }
+
+    pub fn free(&mut self, operand: InsnOperand) {
+        match operand {
+            InsnOperand::Stack(n) => self.stack[n] = false,
             _ => {
-                return Err("Unexpected token");
+                if let Some(i) = self.registers.iter().position(|reg| *reg == operand) {
+                    self.used[i] = false;
+                }
             }
-        },
+        }
     }
 
-    Ok(insns)
+    // The stack slots used for spilling have to exist before they are written,
+    // so the program starts by pushing one for each of them
+    pub fn push_stack_slots(&self, insns: &mut Vec<Instruction>) {
+        for _ in 0..self.stack.len() {
+            insns.insert(
+                0,
+                Instruction {
+                    opcode: InsnOpcode::Push,
+                    operands: vec![InsnOperand::Imm(0)],
+                    span: None,
+                },
+            );
+        }
+    }
 }
 
-struct RegisterAllocation {
-    registers: Vec<InsnOperand>,
-    stack_index: usize,
+impl Default for RegisterAllocation {
+    fn default() -> RegisterAllocation {
+        RegisterAllocation::new(&HELIUM)
+    }
 }
 
-pub fn tree_to_instructions(tree: Node<Token>) -> Result<Vec<Instruction>, &'static str> {
+// A single expression, whose value the program returns with Ret
+pub fn tree_to_instructions(tree: Node<Token>, target: &Target) -> Result<Vec<Instruction>, Error> {
+    compile(vec![tree], InsnOpcode::Ret, target)
+}
+
+// One statement after the other, each of their values written with Out
+pub fn trees_to_instructions(trees: Vec<Node<Token>>, target: &Target) -> Result<Vec<Instruction>, Error> {
+    compile(trees, InsnOpcode::Out, target)
+}
+
+fn compile(trees: Vec<Node<Token>>, result: InsnOpcode, target: &Target) -> Result<Vec<Instruction>, Error> {
+    target.check()?;
     let mut insns: Vec<Instruction> = Vec::new();
-    let mut reg_alloc = RegisterAllocation {
-        registers: vec![Reg0, Reg1, Reg2, Reg3, Reg4, Reg5, Reg6, Reg7],
-        stack_index: 0,
-    };
+    let mut reg_alloc = RegisterAllocation::new(target);
 
-    match node_to_instructions(&mut reg_alloc, tree) {
-        Err(err) => return Err(err),
-        Ok(instructions) => {
-            for insn in instructions {
-                insns.push(insn)
-            }
-        }
+    for tree in &trees {
+        let value = node_to_instructions(&mut reg_alloc, &mut insns, tree)?;
+        insns.push(Instruction {
+            opcode: result,
+            operands: vec![value],
+            span: tree.span(),
+        });
+        reg_alloc.free(value);
     }
-
-    for _ in 0..reg_alloc.stack_index {
-        insns.insert(
-            0,
-            Instruction {
-                opcode: InsnOpcode::Push,
-                operands: vec![InsnOperand::Imm(0)],
-            },
-        );
+    if result != InsnOpcode::Ret {
+        insns.push(Instruction {
+            opcode: InsnOpcode::Halt,
+            operands: vec![],
+            span: None,
+        });
     }
+    reg_alloc.push_stack_slots(&mut insns);
 
     Ok(insns)
 }
 
-impl Display for Instruction {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // Opc OP1, Op2, .. OpN
-        let mut buff = String::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::{branch, leaf};
+    use crate::vm::{run, Value};
 
-        for i in 0 .. self.operands.len() {
-            if i == 0 {
-                buff.push_str(&format!("{:?}", &self.operands[i]));
-                continue;
-            }
+    // every operator over two full subtrees, down to constants
+    fn full(depth: usize) -> Node<Token> {
+        match depth {
+            0 => leaf(1),
+            _ => branch(Operand::Add, full(depth - 1), full(depth - 1)),
+        }
+    }
 
-            buff.push_str(&format!(", {:?} ", &self.operands[i]));
+    fn pushes(insns: &[Instruction]) -> usize {
+        insns.iter().filter(|insn| insn.opcode == InsnOpcode::Push).count()
+    }
 
-        }
+    #[test]
+    fn spills_only_past_the_register_file() {
+        // the constants at the bottom are immediates, so depth n needs n registers
+        assert_eq!(registers_needed(&full(8)), HELIUM.registers);
+        let insns = tree_to_instructions(full(8), &HELIUM).unwrap();
+        assert_eq!(pushes(&insns), 0);
+        assert!(!insns.iter().flat_map(|insn| &insn.operands).any(|op| matches!(op, InsnOperand::Stack(_))));
+        assert_eq!(run(insns).unwrap(), Value::Int(256));
 
-        write!(f, "{:?} {}", self.opcode, buff)
+        assert_eq!(registers_needed(&full(9)), HELIUM.registers + 1);
+        let insns = tree_to_instructions(full(9), &HELIUM).unwrap();
+        assert_eq!(pushes(&insns), 1);
+        assert!(insns.iter().flat_map(|insn| &insn.operands).all(|op| !matches!(op, InsnOperand::Stack(n) if *n > 0)));
+        assert_eq!(run(insns).unwrap(), Value::Int(512));
     }
-}
\ No newline at end of file
+}
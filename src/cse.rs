@@ -0,0 +1,90 @@
+// Common subexpression elimination on the SSA IR: a pure instruction that
+// computes the same thing as an earlier one is dropped, and its value is
+// replaced by the earlier one's everywhere after it
+use crate::ir::{Function, Op, Var};
+use std::collections::HashMap;
+
+// The op with its operands renamed to the values they were replaced by
+fn rename(op: Op, replaced: &HashMap<Var, Var>) -> Op {
+    let r = |var: Var| *replaced.get(&var).unwrap_or(&var);
+
+    match op {
+        Op::Const(_) | Op::Halt => op,
+        // operands of commuting operators in a fixed order, so a*b and b*a match
+        Op::Add(a, b) => Op::Add(r(a).min(r(b)), r(a).max(r(b))),
+        Op::Mul(a, b) => Op::Mul(r(a).min(r(b)), r(a).max(r(b))),
+        Op::Sub(a, b) => Op::Sub(r(a), r(b)),
+        Op::Div(a, b) => Op::Div(r(a), r(b)),
+        Op::Out(a) => Op::Out(r(a)),
+        Op::Ret(a) => Op::Ret(r(a)),
+    }
+}
+
+pub fn eliminate_common_subexpressions(func: Function) -> Function {
+    // pure op -> the value that first computed it
+    let mut available: HashMap<Op, Var> = HashMap::new();
+    let mut replaced: HashMap<Var, Var> = HashMap::new();
+    let mut insts = Vec::new();
+
+    for mut inst in func.insts {
+        inst.op = rename(inst.op, &replaced);
+
+        if let (Some(dst), true) = (inst.dst, inst.op.is_pure()) {
+            match available.get(&inst.op) {
+                Some(earlier) => {
+                    replaced.insert(dst, *earlier);
+                    continue;
+                }
+                None => {
+                    available.insert(inst.op, dst);
+                }
+            }
+        }
+        insts.push(inst);
+    }
+
+    Function {
+        insts,
+        vars: func.vars,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::{branch, leaf};
+    use crate::helium::HELIUM;
+    use crate::ir::{ir_to_instructions, tree_to_ir, trees_to_ir};
+    use crate::token::{tokenize, Operand};
+    use crate::tree::tokens_to_trees;
+    use crate::vm::{run, Value};
+
+    #[test]
+    fn shared_subexpressions() {
+        // (2*3+4)*(2*3-4), which the parser can't express without parentheses
+        let product = || branch(Operand::Mul, leaf(2), leaf(3));
+        let tree = branch(
+            Operand::Mul,
+            branch(Operand::Add, product(), leaf(4)),
+            branch(Operand::Sub, product(), leaf(4)),
+        );
+        let func = eliminate_common_subexpressions(tree_to_ir(&tree).unwrap());
+
+        assert_eq!(
+            func.to_string(),
+            "%0 = const 2\n%1 = const 3\n%2 = mul %0, %1\n%3 = const 4\n%4 = add %2, %3\n%9 = sub %2, %3\n%10 = mul %4, %9\nret %10\n"
+        );
+        assert_eq!(run(ir_to_instructions(&func, &HELIUM).unwrap()).unwrap(), Value::Int(20));
+    }
+
+    #[test]
+    fn outputs_stay() {
+        let trees = tokens_to_trees(tokenize("1+2;1+2".as_bytes()).unwrap()).unwrap();
+        let func = eliminate_common_subexpressions(trees_to_ir(&trees).unwrap());
+        assert_eq!(
+            func.to_string(),
+            "%0 = const 1\n%1 = const 2\n%2 = add %0, %1\nout %2\nout %2\nhalt\n"
+        );
+        assert_eq!(run(ir_to_instructions(&func, &HELIUM).unwrap()).unwrap(), Value::List(vec![3, 3]));
+    }
+}
@@ -0,0 +1,134 @@
+// The one error type every phase of the pipeline returns, so callers can tell
+// what went wrong and where without parsing messages
+use crate::token::Span;
+use crate::vm::{Limit, Partial};
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // reading the source or a file failed
+    Io(io::Error),
+    // a character or number the tokenizer doesn't understand
+    Tokenize { message: String, span: Span },
+    Parse { message: String, span: Option<Span> },
+    // evaluating the tree directly
+    Eval { message: String, span: Option<Span> },
+    // turning a tree or the IR into bytecode
+    Compile { message: String, span: Option<Span> },
+    // line is 1 based
+    Assemble { message: String, line: usize },
+    // a program that doesn't fit in a .hbc file
+    Encode { message: String },
+    // a .hbc file that can't be read back
+    Decode { message: String },
+    Verify { message: String, index: usize },
+    // index is the instruction that failed, span the source it came from if known
+    Runtime { message: String, index: usize, span: Option<Span> },
+    // the program ran into one of its vm::Limits before instruction index,
+    // partial is the state it was stopped in
+    Limit { limit: Limit, index: usize, span: Option<Span>, partial: Box<Partial> },
+}
+
+impl Error {
+    pub fn compile(message: &str, span: Option<Span>) -> Error {
+        Error::Compile {
+            message: String::from(message),
+            span,
+        }
+    }
+
+    // The part of the source the error is about, if it's known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Tokenize { span, .. } => Some(*span),
+            Error::Parse { span, .. }
+            | Error::Eval { span, .. }
+            | Error::Compile { span, .. }
+            | Error::Runtime { span, .. }
+            | Error::Limit { span, .. } => *span,
+            Error::Io(_)
+            | Error::Assemble { .. }
+            | Error::Encode { .. }
+            | Error::Decode { .. }
+            | Error::Verify { .. } => None,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::Io(err) => err.to_string(),
+            Error::Tokenize { message, .. }
+            | Error::Parse { message, .. }
+            | Error::Eval { message, .. }
+            | Error::Compile { message, .. }
+            | Error::Assemble { message, .. }
+            | Error::Encode { message }
+            | Error::Decode { message }
+            | Error::Verify { message, .. }
+            | Error::Runtime { message, .. } => message.clone(),
+            Error::Limit { limit: Limit::Fuel(n), .. } => format!("Ran out of fuel after {} instructions", n),
+            Error::Limit { limit: Limit::Stack(n), .. } => format!("Stack limit of {} slots exceeded", n),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(_) => write!(f, "I/O error")?,
+            Error::Tokenize { .. } => write!(f, "Syntax error")?,
+            Error::Parse { .. } => write!(f, "Parse error")?,
+            Error::Eval { .. } => write!(f, "Evaluation error")?,
+            Error::Compile { .. } => write!(f, "Compile error")?,
+            Error::Assemble { line, .. } => write!(f, "Assembly error on line {}", line)?,
+            Error::Encode { .. } | Error::Decode { .. } => write!(f, "Bytecode error")?,
+            Error::Verify { index, .. } => write!(f, "Verification error in instruction {}", index)?,
+            Error::Runtime { index, .. } => write!(f, "Runtime error in instruction {}", index)?,
+            Error::Limit { index, .. } => write!(f, "Limit reached in instruction {}", index)?,
+        }
+
+        if let Some(span) = self.span() {
+            write!(f, " at {}..{}", span.start, span.end)?;
+        }
+        write!(f, ": {}", self.message())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::trees_to_instructions;
+    use crate::helium::HELIUM;
+    use crate::token::tokenize;
+    use crate::tree::tokens_to_trees;
+    use crate::vm::run;
+
+    #[test]
+    fn positions() {
+        let err = tokenize("1+x".as_bytes()).unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 2, end: 3 }));
+        assert_eq!(err.to_string(), "Syntax error at 2..3: Unexpected character: 'x'");
+
+        let trees = tokens_to_trees(tokenize("1+2\n7-3/0".as_bytes()).unwrap()).unwrap();
+        let err = run(trees_to_instructions(trees, &HELIUM).unwrap()).unwrap_err();
+        assert!(matches!(err, Error::Runtime { .. }));
+        assert_eq!(err.span(), Some(Span { start: 6, end: 9 }));
+        assert_eq!(err.message(), "Division by zero");
+    }
+}
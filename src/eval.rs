@@ -0,0 +1,77 @@
+use crate::error::Error;
+use crate::token::{Opcode, Operand, Token};
+use crate::tree::Node;
+
+// Computes the result straight from the parse tree, without going through bytecode
+pub fn eval_tree(node: &Node<Token>) -> Result<i32, Error> {
+    let error = |message: String| Error::Eval {
+        message,
+        span: node.span(),
+    };
+
+    match &node.value {
+        None => match &node.left {
+            None => Err(error(String::from("Nothing to evaluate"))),
+            Some(left) => eval_tree(left),
+        },
+        Some(token) => match token.op {
+            Opcode::Const => match token.or {
+                Operand::Int(x) => Ok(x),
+                _ => Err(error(format!("Invalid constant: {:?}", token))),
+            },
+            Opcode::Separator => Err(error(String::from("Unexpected separator"))),
+            Opcode::Operand => {
+                let lhs = match &node.left {
+                    None => return Err(error(format!("No left hand side value for {:?}", token.or))),
+                    Some(left) => eval_tree(left)?,
+                };
+                let rhs = match &node.right {
+                    None => return Err(error(format!("No right hand side value for {:?}", token.or))),
+                    Some(right) => eval_tree(right)?,
+                };
+
+                match token.or.apply(lhs, rhs) {
+                    Err(err) => Err(error(String::from(err))),
+                    Ok(v) => Ok(v),
+                }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokenize;
+    use crate::tree::tokens_to_tree;
+
+    fn eval_source(source: &str) -> Result<i32, Error> {
+        eval_tree(&tokens_to_tree(tokenize(source.as_bytes()).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn grouping() {
+        // the parser has no precedence: each operator takes everything to its right
+        assert_eq!(eval_source("2*3+4").unwrap(), 14);
+        assert_eq!(eval_source("2+3*4").unwrap(), 14);
+        assert_eq!(eval_source("8-2-1").unwrap(), 7);
+        assert_eq!(eval_source("7/2").unwrap(), 3);
+    }
+
+    #[test]
+    fn lone_numbers() {
+        assert_eq!(eval_source("5").unwrap(), 5);
+        assert_eq!(eval_source("2147483647").unwrap(), i32::MAX);
+    }
+
+    #[test]
+    fn errors() {
+        let err = eval_source("1+4/0").unwrap_err();
+        assert!(matches!(err, Error::Eval { .. }));
+        assert_eq!(err.message(), "Division by zero");
+        assert_eq!(err.span().map(|span| (span.start, span.end)), Some((2, 5)));
+
+        assert_eq!(eval_source("2147483647+1").unwrap_err().message(), "Arithmetic overflow");
+        assert_eq!(eval_source("65536*65536").unwrap_err().message(), "Arithmetic overflow");
+    }
+}
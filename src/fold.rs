@@ -0,0 +1,71 @@
+use crate::token::{Opcode, Operand, Token};
+use crate::tree::Node;
+
+// Folds every operator whose operands are both constants into a single constant.
+// Subtrees that would fail at runtime (division by zero, overflow) are left alone
+// so the vm still reports the error.
+pub fn fold_constants(node: Node<Token>) -> Node<Token> {
+    let span = node.span();
+    let left = node.left.map(|left| Box::new(fold_constants(*left)));
+    let right = node.right.map(|right| Box::new(fold_constants(*right)));
+
+    if let Some(token) = &node.value {
+        if token.op == Opcode::Operand {
+            let lhs = left.as_ref().and_then(|left| left.constant());
+            let rhs = right.as_ref().and_then(|right| right.constant());
+            if let (Some(lhs), Some(rhs)) = (lhs, rhs) {
+                if let Ok(res) = token.or.apply(lhs, rhs) {
+                    return Node {
+                        value: Some(Box::new(Token {
+                            op: Opcode::Const,
+                            or: Operand::Int(res),
+                            // errors further up still point at the whole subexpression
+                            span: span.unwrap_or(token.span),
+                        })),
+                        left: None,
+                        right: None,
+                    };
+                }
+            }
+        }
+    }
+
+    Node {
+        value: node.value,
+        left,
+        right,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::token::tokenize;
+    use crate::tree::tokens_to_tree;
+
+    fn folded(source: &str) -> Node<Token> {
+        fold_constants(tokens_to_tree(tokenize(source.as_bytes()).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn constants() {
+        let tree = folded("2*3+4");
+        assert_eq!(tree.value.as_ref().map(|token| token.op), Some(Opcode::Const));
+        assert_eq!(tree.constant(), Some(14));
+        assert!(tree.left.is_none() && tree.right.is_none());
+    }
+
+    #[test]
+    fn errors_stay() {
+        for (source, message) in [("1/0", "Division by zero"), ("2147483647+1", "Arithmetic overflow")] {
+            let tree = folded(source);
+            assert_eq!(tree.value.as_ref().map(|token| token.op), Some(Opcode::Operand), "{}", source);
+
+            match crate::eval(source) {
+                Err(err @ Error::Runtime { .. }) => assert_eq!(err.message(), message),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,516 @@
+// Differential fuzzing: every generated expression goes through
+// tokenize -> parse -> compile -> run and has to agree with eval on the same tree
+use crate::bytecode::tree_to_instructions;
+use crate::cse::eliminate_common_subexpressions;
+use crate::error::Error;
+use crate::eval::eval_tree;
+use crate::fold::fold_constants;
+use crate::helium::verify::verify;
+use crate::helium::{Target, HELIUM};
+use crate::ir::{ir_to_instructions, tree_to_ir};
+use crate::peephole::{default_rules, optimize};
+use crate::token::{tokenize, Opcode, Operand, Span, Token};
+use crate::tree::{tokens_to_tree, Node};
+use crate::vm::{run, run_on, Value};
+
+// xorshift64*, good enough to get reproducible expressions out of a seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck on 0
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    // one more number than there are operands
+    pub nums: Vec<i32>,
+    pub ops: Vec<Operand>,
+}
+
+impl Expr {
+    pub fn generate(rng: &mut Rng, max_ops: usize) -> Expr {
+        let count = rng.below(max_ops as u64 + 1) as usize;
+        let mut nums = vec![gen_num(rng)];
+        let mut ops = Vec::new();
+
+        for _ in 0..count {
+            ops.push(gen_op(rng));
+            nums.push(gen_num(rng));
+        }
+
+        Expr { nums, ops }
+    }
+
+    pub fn source(&self) -> String {
+        let mut buff = self.nums[0].to_string();
+
+        for (op, num) in self.ops.iter().zip(&self.nums[1..]) {
+            buff.push(match op {
+                Operand::Add => '+',
+                Operand::Sub => '-',
+                Operand::Mul => '*',
+                _ => '/',
+            });
+            buff.push_str(&num.to_string());
+        }
+
+        buff
+    }
+
+}
+
+pub trait Shrink: Sized {
+    // Smaller variants of this case, tried in order while shrinking
+    fn candidates(&self) -> Vec<Self>;
+}
+
+impl Shrink for Expr {
+    // one operator dropped, or one number made simpler
+    fn candidates(&self) -> Vec<Expr> {
+        let mut out = Vec::new();
+
+        for i in 0..self.ops.len() {
+            let mut smaller = self.clone();
+            smaller.ops.remove(i);
+            smaller.nums.remove(i + 1);
+            out.push(smaller);
+
+            let mut smaller = self.clone();
+            smaller.ops.remove(i);
+            smaller.nums.remove(i);
+            out.push(smaller);
+        }
+
+        for i in 0..self.nums.len() {
+            for num in [0, 1, self.nums[i] / 2] {
+                if num < self.nums[i] {
+                    let mut smaller = self.clone();
+                    smaller.nums[i] = num;
+                    out.push(smaller);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn gen_op(rng: &mut Rng) -> Operand {
+    match rng.below(4) {
+        0 => Operand::Add,
+        1 => Operand::Sub,
+        2 => Operand::Mul,
+        _ => Operand::Div,
+    }
+}
+
+fn gen_num(rng: &mut Rng) -> i32 {
+    match rng.below(10) {
+        0..=6 => rng.below(10) as i32,
+        7 | 8 => rng.below(1000) as i32,
+        // big enough to overflow once multiplied
+        _ => i32::MAX - rng.below(1000) as i32,
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Value(Value),
+    // failed at runtime, or in eval, with the message
+    Error(String),
+    // the compiler or the verifier rejected the program. eval can't fail that
+    // way, so this never agrees with anything, not even itself
+    Miscompiled(String),
+}
+
+impl PartialEq for Outcome {
+    fn eq(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (Outcome::Value(a), Outcome::Value(b)) => a == b,
+            (Outcome::Error(a), Outcome::Error(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+pub struct Report {
+    pub expected: Outcome,
+    // what each subexpression that fails with both of its operands fine fails
+    // with. With more than one of them, which comes first depends on the order
+    // they're evaluated in, and Sethi-Ullman numbering changes that, so an
+    // error agrees with eval if it's any of these
+    pub failures: Vec<String>,
+    pub compiled: Outcome,
+    // folded and run through the peephole optimizer, like main does
+    pub optimized: Outcome,
+    // built into the SSA IR and lowered from there
+    pub ir: Outcome,
+    // the same with common subexpressions eliminated
+    pub cse: Outcome,
+}
+
+impl Report {
+    pub fn agrees(&self) -> bool {
+        [&self.compiled, &self.optimized, &self.ir, &self.cse].into_iter().all(|outcome| self.allows(outcome))
+    }
+
+    fn allows(&self, outcome: &Outcome) -> bool {
+        match (&self.expected, outcome) {
+            (Outcome::Error(_), Outcome::Error(message)) => self.failures.contains(message),
+            _ => self.expected == *outcome,
+        }
+    }
+
+    // What the compiler or the verifier said, if a pipeline was rejected
+    pub fn rejection(&self) -> Option<&str> {
+        [&self.compiled, &self.optimized, &self.ir, &self.cse].into_iter().find_map(|outcome| match outcome {
+            Outcome::Miscompiled(message) => Some(message.as_str()),
+            _ => None,
+        })
+    }
+}
+
+fn run_ir(tree: &Node<Token>, cse: bool) -> Outcome {
+    match tree_to_ir(tree) {
+        Err(err) => Outcome::Miscompiled(err.to_string()),
+        Ok(mut func) => {
+            if cse {
+                func = eliminate_common_subexpressions(func);
+            }
+            match ir_to_instructions(&func, &HELIUM) {
+                Err(err) => Outcome::Miscompiled(err.to_string()),
+                Ok(insns) => match verify(&insns, &HELIUM) {
+                    Err(err) => Outcome::Miscompiled(err.to_string()),
+                    Ok(()) => outcome(run(insns)),
+                },
+            }
+        }
+    }
+}
+
+fn compile_and_run(src: &str, optimized: bool) -> Outcome {
+    let tokens = tokenize(src.as_bytes()).unwrap();
+    let mut tree = tokens_to_tree(tokens).unwrap();
+    if optimized {
+        tree = fold_constants(tree);
+    }
+
+    match tree_to_instructions(tree, &HELIUM) {
+        Err(err) => Outcome::Miscompiled(err.to_string()),
+        Ok(mut insns) => {
+            if optimized {
+                insns = optimize(insns, &default_rules());
+            }
+            match verify(&insns, &HELIUM) {
+                Err(err) => Outcome::Miscompiled(err.to_string()),
+                Ok(()) => outcome(run(insns)),
+            }
+        }
+    }
+}
+
+pub fn check(expr: &Expr) -> Report {
+    let src = expr.source();
+    let tree = tokens_to_tree(tokenize(src.as_bytes()).unwrap()).unwrap();
+
+    Report {
+        expected: outcome(eval_tree(&tree).map(Value::Int)),
+        failures: failures(&tree),
+        compiled: compile_and_run(&src, false),
+        optimized: compile_and_run(&src, true),
+        ir: run_ir(&tree, false),
+        cse: run_ir(&tree, true),
+    }
+}
+
+// The parser only ever builds right leaning chains, so balanced trees that need
+// more registers than there are get built by hand
+#[derive(Debug, Clone)]
+pub struct Tree(pub Node<Token>);
+
+pub fn leaf(num: i32) -> Node<Token> {
+    Node {
+        value: Some(Box::new(Token {
+            op: Opcode::Const,
+            or: Operand::Int(num),
+            span: Span { start: 0, end: 0 },
+        })),
+        left: None,
+        right: None,
+    }
+}
+
+pub fn branch(op: Operand, left: Node<Token>, right: Node<Token>) -> Node<Token> {
+    Node {
+        value: Some(Box::new(Token {
+            op: Opcode::Operand,
+            or: op,
+            span: Span { start: 0, end: 0 },
+        })),
+        left: Some(Box::new(left)),
+        right: Some(Box::new(right)),
+    }
+}
+
+impl Tree {
+    pub fn generate(rng: &mut Rng, depth: usize) -> Tree {
+        Tree(gen_node(rng, depth))
+    }
+}
+
+fn gen_node(rng: &mut Rng, depth: usize) -> Node<Token> {
+    // full trees are the ones that need the most registers, and mostly adding
+    // keeps hundreds of leaves from overflowing
+    if depth == 0 {
+        return leaf(rng.below(9) as i32 + 1);
+    }
+
+    let op = match rng.below(20) {
+        0..=8 => Operand::Add,
+        9..=17 => Operand::Sub,
+        18 => Operand::Mul,
+        _ => Operand::Div,
+    };
+    branch(op, gen_node(rng, depth - 1), gen_node(rng, depth - 1))
+}
+
+impl Shrink for Tree {
+    // an operator replaced by one of its operands, or a number made simpler
+    fn candidates(&self) -> Vec<Tree> {
+        node_candidates(&self.0).into_iter().map(Tree).collect()
+    }
+}
+
+fn node_candidates(node: &Node<Token>) -> Vec<Node<Token>> {
+    let mut out = Vec::new();
+    let token = node.value.as_ref().unwrap();
+
+    match (&node.left, &node.right) {
+        (Some(left), Some(right)) => {
+            out.push((**left).clone());
+            out.push((**right).clone());
+            for smaller in node_candidates(left) {
+                out.push(branch(token.or, smaller, (**right).clone()));
+            }
+            for smaller in node_candidates(right) {
+                out.push(branch(token.or, (**left).clone(), smaller));
+            }
+        }
+        _ => {
+            if let Operand::Int(num) = token.or {
+                for smaller in [0, 1, num / 2] {
+                    if smaller < num {
+                        out.push(leaf(smaller));
+                    }
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn outcome(res: Result<Value, Error>) -> Outcome {
+    match res {
+        Err(err) => Outcome::Error(err.message()),
+        Ok(v) => Outcome::Value(v),
+    }
+}
+
+fn failures(node: &Node<Token>) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let children: Vec<&Node<Token>> = [&node.left, &node.right].into_iter().flatten().map(|child| &**child).collect();
+    for child in &children {
+        out.extend(failures(child));
+    }
+
+    if let Err(err) = eval_tree(node) {
+        if children.iter().all(|child| eval_tree(child).is_ok()) {
+            out.push(err.message());
+        }
+    }
+
+    out
+}
+
+pub fn check_tree(tree: &Tree) -> Report {
+    // verified after optimizing, the way compile_for does it
+    let run_tree = |tree: Node<Token>, optimized: bool| match tree_to_instructions(tree, &HELIUM) {
+        Err(err) => Outcome::Miscompiled(err.to_string()),
+        Ok(mut insns) => {
+            if optimized {
+                insns = optimize(insns, &default_rules());
+            }
+            match verify(&insns, &HELIUM) {
+                Err(err) => Outcome::Miscompiled(err.to_string()),
+                Ok(()) => outcome(run(insns)),
+            }
+        }
+    };
+
+    Report {
+        expected: outcome(eval_tree(&tree.0).map(Value::Int)),
+        failures: failures(&tree.0),
+        compiled: run_tree(tree.0.clone(), false),
+        optimized: run_tree(fold_constants(tree.0.clone()), true),
+        ir: run_ir(&tree.0, false),
+        cse: run_ir(&tree.0, true),
+    }
+}
+
+// Greedily shrinks a failing case until no smaller candidate fails anymore
+pub fn shrink<T: Shrink, F: Fn(&T) -> bool>(mut case: T, fails: F) -> T {
+    loop {
+        match case.candidates().into_iter().find(|candidate| fails(candidate)) {
+            None => return case,
+            Some(smaller) => case = smaller,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fuzz(seed: u64, cases: usize, max_ops: usize) {
+        let mut rng = Rng::new(seed);
+
+        for _ in 0..cases {
+            let expr = Expr::generate(&mut rng, max_ops);
+            if !check(&expr).agrees() {
+                let minimal = shrink(expr.clone(), |candidate| !check(candidate).agrees());
+                let report = check(&minimal);
+                panic!(
+                    "pipeline disagrees with eval (seed {}) on {}\nminimal: {}\neval: {:?}, compiled: {:?}, optimized: {:?}, ir: {:?}, cse: {:?}\nrejected: {:?}",
+                    seed,
+                    expr.source(),
+                    minimal.source(),
+                    report.expected,
+                    report.compiled,
+                    report.optimized,
+                    report.ir,
+                    report.cse,
+                    report.rejection()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_expressions() {
+        fuzz(1, 2000, 3);
+    }
+
+    #[test]
+    fn long_expressions() {
+        fuzz(2, 2000, 12);
+    }
+
+    #[test]
+    fn spilling_trees() {
+        let mut rng = Rng::new(3);
+
+        for _ in 0..300 {
+            // a full tree of depth 9 needs nine registers, the constants at
+            // the bottom are immediates
+            let tree = Tree::generate(&mut rng, 9);
+            if !check_tree(&tree).agrees() {
+                let minimal = shrink(tree, |candidate| !check_tree(candidate).agrees());
+                let report = check_tree(&minimal);
+                panic!(
+                    "compiled tree disagrees with eval\nminimal: {:#?}\neval: {:?}, compiled: {:?}, optimized: {:?}, ir: {:?}, cse: {:?}\nrejected: {:?}",
+                    minimal.0,
+                    report.expected,
+                    report.compiled,
+                    report.optimized,
+                    report.ir,
+                    report.cse,
+                    report.rejection()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn small_register_files() {
+        let mut rng = Rng::new(4);
+
+        for registers in 1..=3 {
+            let target = Target { registers };
+            for _ in 0..50 {
+                let tree = Tree::generate(&mut rng, 5);
+                let insns = tree_to_instructions(tree.0.clone(), &target).unwrap();
+                verify(&insns, &target).unwrap();
+                let report = Report {
+                    compiled: outcome(run_on(&target, insns)),
+                    ..check_tree(&tree)
+                };
+
+                assert!(report.agrees(), "{:#?}\n{:?} vs {:?}", tree.0, report.compiled, report.expected);
+            }
+        }
+    }
+
+    #[test]
+    fn miscompiles_never_agree() {
+        let rejected = || Outcome::Miscompiled(String::from("Verify error"));
+        assert_ne!(rejected(), rejected());
+        let error = |message: &str| Outcome::Error(String::from(message));
+        assert_ne!(rejected(), error("Verify error"));
+        assert_ne!(error("Verify error"), rejected());
+        assert_eq!(error("Division by zero"), error("Division by zero"));
+        assert_ne!(error("Division by zero"), error("Arithmetic overflow"));
+    }
+
+    #[test]
+    fn errors_have_to_match() {
+        // one failing subexpression, so eval and the vm have to fail the same way
+        let report = check(&Expr {
+            nums: vec![2147483647, 2, 0],
+            ops: vec![Operand::Mul, Operand::Div],
+        });
+        assert_eq!(report.failures, vec![String::from("Division by zero")]);
+        assert!(report.agrees());
+        let wrong = Report {
+            ir: Outcome::Error(String::from("Arithmetic overflow")),
+            ..report
+        };
+        assert!(!wrong.agrees());
+
+        // both sides fail on their own, either error is right
+        let tree = Tree(branch(
+            Operand::Add,
+            branch(Operand::Div, leaf(1), leaf(0)),
+            branch(Operand::Sub, leaf(0), branch(Operand::Mul, leaf(i32::MAX), leaf(2))),
+        ));
+        let report = check_tree(&tree);
+        assert_eq!(report.failures.len(), 2);
+        assert!(report.agrees());
+    }
+
+    #[test]
+    fn shrinks_to_minimal_case() {
+        let expr = Expr {
+            nums: vec![7, 3, 0, 5],
+            ops: vec![Operand::Add, Operand::Div, Operand::Mul],
+        };
+        // pretend every expression with a division fails
+        let minimal = shrink(expr, |candidate| candidate.ops.contains(&Operand::Div));
+
+        assert_eq!(minimal.source(), "0/0");
+    }
+}
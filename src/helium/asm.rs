@@ -0,0 +1,212 @@
+// Textual Helium assembly, the same format Display for Instruction prints
+//
+//   ; comments run from ';' or '#' to the end of the line
+//   start:                 labels name the instruction that follows them
+//   0: Ldc Reg0, Imm(6)    a leading instruction index (as in listings) is skipped
+//   Add Reg0, 5            a bare number is the same as Imm(5)
+use crate::error::Error;
+use crate::helium::{InsnOperand, Instruction, OPCODES};
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Assembly {
+    pub instructions: Vec<Instruction>,
+    // label name -> index of the instruction it points at
+    pub labels: HashMap<String, usize>,
+}
+
+pub fn assemble(text: &str) -> Result<Assembly, Error> {
+    let mut asm = Assembly::default();
+
+    for (line_no, line) in text.lines().enumerate() {
+        let line_no = line_no + 1;
+        let error = |message: String| Error::Assemble {
+            message,
+            line: line_no,
+        };
+        let mut line = match line.find([';', '#']) {
+            None => line,
+            Some(comment) => &line[..comment],
+        }
+        .trim();
+
+        // any number of labels or listing indices can come before the instruction
+        while let Some(colon) = line.find(':') {
+            let name = line[..colon].trim();
+            if name.chars().all(|c| c.is_ascii_digit()) && !name.is_empty() {
+                // listing index, only checked for consistency
+                if name.parse::<usize>() != Ok(asm.instructions.len()) {
+                    return Err(error(format!(
+                        "instruction index {} doesn't match its position {}",
+                        name,
+                        asm.instructions.len()
+                    )));
+                }
+            } else if is_identifier(name) {
+                if asm
+                    .labels
+                    .insert(String::from(name), asm.instructions.len())
+                    .is_some()
+                {
+                    return Err(error(format!("duplicate label `{}`", name)));
+                }
+            } else {
+                return Err(error(format!("invalid label `{}`", name)));
+            }
+
+            line = line[colon + 1..].trim();
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, rest) = match line.find(char::is_whitespace) {
+            None => (line, ""),
+            Some(space) => (&line[..space], line[space..].trim()),
+        };
+        let opcode = match OPCODES
+            .iter()
+            .find(|op| format!("{:?}", op).eq_ignore_ascii_case(name))
+        {
+            None => return Err(error(format!("unknown opcode `{}`", name))),
+            Some(opcode) => *opcode,
+        };
+
+        let mut operands: Vec<InsnOperand> = Vec::new();
+        if !rest.is_empty() {
+            for operand in rest.split(',') {
+                match parse_operand(operand.trim()) {
+                    None => {
+                        return Err(error(format!("invalid operand `{}`", operand.trim())))
+                    }
+                    Some(operand) => operands.push(operand),
+                }
+            }
+        }
+
+        asm.instructions.push(Instruction {
+            opcode,
+            operands,
+            span: None,
+        });
+    }
+
+    Ok(asm)
+}
+
+fn is_identifier(name: &str) -> bool {
+    match name.chars().next() {
+        None => false,
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+    }
+}
+
+// One operand as written in assembly: 5, Imm(5), Stack(0) or Reg3
+pub fn parse_operand(text: &str) -> Option<InsnOperand> {
+    if let Ok(n) = text.parse::<i32>() {
+        return Some(InsnOperand::Imm(n));
+    }
+    if text.get(..3).is_some_and(|prefix| prefix.eq_ignore_ascii_case("reg")) {
+        return text[3..].parse::<u8>().ok().map(InsnOperand::Reg);
+    }
+
+    let open = text.find('(')?;
+    let inner = text[open + 1..].strip_suffix(')')?.trim();
+    match text[..open].trim().to_ascii_lowercase().as_str() {
+        "imm" => inner.parse::<i32>().ok().map(InsnOperand::Imm),
+        "stack" => inner.parse::<usize>().ok().map(InsnOperand::Stack),
+        _ => None,
+    }
+}
+
+// The listing main prints, which assemble reads back into the same instructions
+pub fn disassemble(insns: &[Instruction], labels: &HashMap<String, usize>) -> String {
+    let mut names: Vec<(&usize, &String)> = labels.iter().map(|(name, i)| (i, name)).collect();
+    names.sort();
+
+    let mut buff = String::new();
+    for (index, insn) in insns.iter().enumerate() {
+        for (_, name) in names.iter().filter(|(i, _)| **i == index) {
+            buff.push_str(&format!("{}:\n", name));
+        }
+        buff.push_str(&format!("{}: {}\n", index, insn));
+    }
+    // labels can also point just past the last instruction
+    for (_, name) in names.iter().filter(|(i, _)| **i >= insns.len()) {
+        buff.push_str(&format!("{}:\n", name));
+    }
+
+    buff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::InsnOpcode;
+
+    fn insn(opcode: InsnOpcode, operands: Vec<InsnOperand>) -> Instruction {
+        Instruction {
+            opcode,
+            operands,
+            span: None,
+        }
+    }
+
+    fn error(text: &str) -> (String, usize) {
+        match assemble(text) {
+            Err(Error::Assemble { message, line }) => (message, line),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        use InsnOperand::*;
+        let insns = vec![
+            insn(InsnOpcode::Push, vec![Imm(0)]),
+            insn(InsnOpcode::Ldc, vec![Reg(0), Imm(-6)]),
+            insn(InsnOpcode::Copy, vec![Stack(0), Reg(0)]),
+            insn(InsnOpcode::Add, vec![Reg(7), Stack(0)]),
+            insn(InsnOpcode::Out, vec![Reg(7)]),
+            insn(InsnOpcode::Halt, vec![]),
+        ];
+        let labels: HashMap<String, usize> =
+            [("start", 0), ("loop", 3), ("_also", 3), ("end", 6)].map(|(name, i)| (String::from(name), i)).into();
+
+        let text = disassemble(&insns, &labels);
+        let asm = assemble(&text).unwrap();
+        assert_eq!(asm.instructions, insns);
+        assert_eq!(asm.labels, labels);
+        assert_eq!(disassemble(&asm.instructions, &asm.labels), text);
+
+        // the same by hand, with comments, bare numbers and labels on the line
+        let text = "; the whole line\n\
+                    start: 0: push 0\n\
+                    ldc reg0, -6   # trailing\n\
+                    2: Copy Stack( 0 ), Reg0\n\
+                    loop: _also:\n\
+                    Add Reg7, stack(0)\n\
+                    Out REG7\n\
+                    5: Halt\n\
+                    end:";
+        let asm = assemble(text).unwrap();
+        assert_eq!(asm.instructions, insns);
+        assert_eq!(asm.labels, labels);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("Halt\nFrob Reg0"), (String::from("unknown opcode `Frob`"), 2));
+        for operand in ["Reg256", "Reg", "Stack(-1)", "Imm(x)", "Imm(1", "Heap(1)", "2147483648", ""] {
+            let (message, line) = error(&format!("Push 1\nAdd Reg0, {}", operand));
+            assert_eq!((message, line), (format!("invalid operand `{}`", operand), 2), "{}", operand);
+        }
+        assert_eq!(error("0: Halt\n2: Halt").0, "instruction index 2 doesn't match its position 1");
+        assert_eq!(error("a: Halt\na: Halt"), (String::from("duplicate label `a`"), 2));
+        assert_eq!(error("1a: Halt").0, "invalid label `1a`");
+    }
+}
@@ -0,0 +1,463 @@
+// Binary Helium bytecode files (.hbc)
+//
+// All integers are little endian.
+//
+//   magic      4 bytes  "HBC\0"
+//   version    u16      VERSION
+//   flags      u16      FLAG_DEBUG or 0, the other bits are reserved
+//   constants  u32 count, then count i32 values
+//   code       u32 count, then count instructions:
+//                u8 opcode, u8 operand count, then per operand
+//                u8 kind: 0 = Imm (u32 index into constants)
+//                         1 = Stack (u32 slot)
+//                         2 = Reg (u8 register number)
+//   debug      only with FLAG_DEBUG:
+//                u32 length, then the UTF-8 source the code was compiled from
+//                per instruction u32 start, u32 end of its span in the source,
+//                NO_SPAN twice for instructions without one
+//   checksum   u32      CRC-32 of every byte before it
+use crate::error::Error;
+use crate::helium::{InsnOperand, Instruction, OPCODES};
+use crate::token::Span;
+
+pub const MAGIC: &[u8; 4] = b"HBC\0";
+pub const VERSION: u16 = 1;
+pub const FLAG_DEBUG: u16 = 1;
+const NO_SPAN: u32 = u32::MAX;
+
+const KIND_IMM: u8 = 0;
+const KIND_STACK: u8 = 1;
+const KIND_REG: u8 = 2;
+
+// Debug info is written when there's a source; without one the spans have
+// nothing to point into and are left out. Fails on a program too big for the
+// format's fields instead of truncating it, and on spans that aren't in the source
+pub fn encode(insns: &[Instruction], source: Option<&str>) -> Result<Vec<u8>, Error> {
+    write(insns, source).map_err(|message| Error::Encode { message })
+}
+
+// vm::snapshot writes its lengths with it too
+pub(crate) fn u32_of(n: usize, what: &str) -> Result<u32, String> {
+    u32::try_from(n).map_err(|_| format!("{} {} doesn't fit in 32 bits", what, n))
+}
+
+fn write(insns: &[Instruction], source: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut constants: Vec<i32> = Vec::new();
+    let mut code: Vec<u8> = Vec::new();
+
+    for (index, insn) in insns.iter().enumerate() {
+        code.push(OPCODES.iter().position(|op| *op == insn.opcode).unwrap() as u8);
+        match u8::try_from(insn.operands.len()) {
+            Err(_) => {
+                return Err(format!(
+                    "Instruction {} has {} operands, at most 255 fit",
+                    index,
+                    insn.operands.len()
+                ))
+            }
+            Ok(count) => code.push(count),
+        }
+
+        for operand in &insn.operands {
+            match operand {
+                InsnOperand::Imm(n) => {
+                    let index = match constants.iter().position(|c| c == n) {
+                        Some(index) => index,
+                        None => {
+                            constants.push(*n);
+                            constants.len() - 1
+                        }
+                    };
+                    code.push(KIND_IMM);
+                    code.extend_from_slice(&u32_of(index, "Constant index")?.to_le_bytes());
+                }
+                InsnOperand::Stack(n) => {
+                    code.push(KIND_STACK);
+                    code.extend_from_slice(&u32_of(*n, "Stack slot")?.to_le_bytes());
+                }
+                InsnOperand::Reg(n) => {
+                    code.push(KIND_REG);
+                    code.push(*n);
+                }
+            }
+        }
+    }
+
+    let debug = source.is_some();
+
+    let mut buff: Vec<u8> = Vec::new();
+    buff.extend_from_slice(MAGIC);
+    buff.extend_from_slice(&VERSION.to_le_bytes());
+    buff.extend_from_slice(&(if debug { FLAG_DEBUG } else { 0 }).to_le_bytes());
+    buff.extend_from_slice(&u32_of(constants.len(), "Constant count")?.to_le_bytes());
+    for constant in &constants {
+        buff.extend_from_slice(&constant.to_le_bytes());
+    }
+    buff.extend_from_slice(&u32_of(insns.len(), "Instruction count")?.to_le_bytes());
+    buff.extend_from_slice(&code);
+
+    if let Some(source) = source {
+        buff.extend_from_slice(&u32_of(source.len(), "Source length")?.to_le_bytes());
+        buff.extend_from_slice(source.as_bytes());
+        for (index, insn) in insns.iter().enumerate() {
+            let (start, end) = match insn.span {
+                None => (NO_SPAN, NO_SPAN),
+                // decode would refuse it
+                Some(span) if source.get(span.start..span.end).is_none() => {
+                    return Err(format!(
+                        "Invalid span {}..{} for instruction {} ({} bytes of source)",
+                        span.start,
+                        span.end,
+                        index,
+                        source.len()
+                    ))
+                }
+                // NO_SPAN itself is taken
+                Some(span) => match (u32_of(span.start, "Span start")?, u32_of(span.end, "Span end")?) {
+                    (start, end) if start == NO_SPAN || end == NO_SPAN => {
+                        return Err(format!("Span {}..{} doesn't fit in 32 bits", span.start, span.end))
+                    }
+                    bounds => bounds,
+                },
+            };
+            buff.extend_from_slice(&start.to_le_bytes());
+            buff.extend_from_slice(&end.to_le_bytes());
+        }
+    }
+
+    let checksum = crc32(&buff);
+    buff.extend_from_slice(&checksum.to_le_bytes());
+    Ok(buff)
+}
+
+// Reads little endian fields, naming what was expected when the bytes run out.
+// vm::snapshot reads its files with it too
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl Reader<'_> {
+    pub fn take(&mut self, n: usize, what: &str) -> Result<&[u8], String> {
+        if self.bytes.len() - self.pos < n {
+            return Err(format!(
+                "Truncated file: expected {} ({} bytes) at offset {}, only {} left",
+                what,
+                n,
+                self.pos,
+                self.bytes.len() - self.pos
+            ));
+        }
+
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    pub fn u8(&mut self, what: &str) -> Result<u8, String> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    pub fn u16(&mut self, what: &str) -> Result<u16, String> {
+        let b = self.take(2, what)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self, what: &str) -> Result<u32, String> {
+        let b = self.take(4, what)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self, what: &str) -> Result<u64, String> {
+        let b = self.take(8, what)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+}
+
+// The instructions, and the source they were compiled from if the file has debug info
+pub fn decode(bytes: &[u8]) -> Result<(Vec<Instruction>, Option<String>), Error> {
+    read(bytes).map_err(|message| Error::Decode { message })
+}
+
+fn read(bytes: &[u8]) -> Result<(Vec<Instruction>, Option<String>), String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(String::from("Not a Helium bytecode file: bad magic number"));
+    }
+    let bytes = checked(bytes)?;
+    let mut r = Reader { bytes, pos: 4 };
+
+    let version = r.u16("version")?;
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {} (expected {})",
+            version, VERSION
+        ));
+    }
+    let flags = r.u16("flags")?;
+    if flags & !FLAG_DEBUG != 0 {
+        return Err(format!("Unknown flags {:#06x}", flags));
+    }
+
+    let count = r.u32("constant count")? as usize;
+    let mut constants: Vec<i32> = Vec::new();
+    for _ in 0..count {
+        constants.push(r.u32("constant")? as i32);
+    }
+
+    let count = r.u32("instruction count")? as usize;
+    let mut insns: Vec<Instruction> = Vec::new();
+    for index in 0..count {
+        let opcode = match OPCODES.get(r.u8("opcode")? as usize) {
+            None => {
+                return Err(format!(
+                    "Unknown opcode {:#04x} in instruction {}",
+                    bytes[r.pos - 1],
+                    index
+                ))
+            }
+            Some(opcode) => *opcode,
+        };
+
+        let mut operands: Vec<InsnOperand> = Vec::new();
+        for _ in 0..r.u8("operand count")? {
+            operands.push(match r.u8("operand kind")? {
+                KIND_IMM => {
+                    let n = r.u32("constant index")? as usize;
+                    match constants.get(n) {
+                        None => {
+                            return Err(format!(
+                                "Constant index {} out of range ({} constants) in instruction {}",
+                                n,
+                                constants.len(),
+                                index
+                            ))
+                        }
+                        Some(c) => InsnOperand::Imm(*c),
+                    }
+                }
+                KIND_STACK => InsnOperand::Stack(r.u32("stack slot")? as usize),
+                // the register file's size is the target's business, the verifier checks it
+                KIND_REG => InsnOperand::Reg(r.u8("register")?),
+                kind => {
+                    return Err(format!(
+                        "Unknown operand kind {} in instruction {}",
+                        kind, index
+                    ))
+                }
+            });
+        }
+
+        insns.push(Instruction {
+            opcode,
+            operands,
+            span: None,
+        });
+    }
+
+    let mut source: Option<String> = None;
+    if flags & FLAG_DEBUG != 0 {
+        let len = r.u32("source length")? as usize;
+        match String::from_utf8(r.take(len, "source")?.to_vec()) {
+            Err(_) => return Err(String::from("Source in the debug info isn't valid UTF-8")),
+            Ok(text) => source = Some(text),
+        }
+
+        for (index, insn) in insns.iter_mut().enumerate() {
+            let start = r.u32("span start")?;
+            let end = r.u32("span end")?;
+            if start == NO_SPAN {
+                continue;
+            }
+            let (start, end) = (start as usize, end as usize);
+            let text = source.as_deref().unwrap_or("");
+            // anything that slices the source by it can trust it from here on
+            if text.get(start..end).is_none() {
+                return Err(format!(
+                    "Invalid span {}..{} for instruction {} ({} bytes of source)",
+                    start,
+                    end,
+                    index,
+                    text.len()
+                ));
+            }
+
+            insn.span = Some(Span { start, end });
+        }
+    }
+
+    if r.pos != bytes.len() {
+        return Err(format!(
+            "{} unexpected bytes before the checksum",
+            bytes.len() - r.pos
+        ));
+    }
+
+    Ok((insns, source))
+}
+
+// The bytes before the trailing checksum, once it matches them. Checked before
+// anything is parsed, so a corrupt file isn't reported as whatever its
+// damage happens to look like. vm::snapshot files end the same way
+pub(crate) fn checked(bytes: &[u8]) -> Result<&[u8], String> {
+    if bytes.len() < 4 {
+        return Err(format!("Truncated file: {} bytes, too short for a checksum", bytes.len()));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) != crc32(body) {
+        return Err(String::from("Checksum mismatch: the file is corrupt"));
+    }
+
+    Ok(body)
+}
+
+// CRC-32 (IEEE), bitwise since the files are tiny
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::InsnOpcode;
+    use crate::{compile, parse, tokenize};
+
+    fn halt() -> Vec<u8> {
+        let insns = [Instruction {
+            opcode: InsnOpcode::Halt,
+            operands: vec![],
+            span: None,
+        }];
+        encode(&insns, None).unwrap()
+    }
+
+    // the checksum recomputed after editing the bytes before it
+    fn resealed(mut bytes: Vec<u8>) -> Vec<u8> {
+        let end = bytes.len() - 4;
+        let checksum = crc32(&bytes[..end]);
+        bytes[end..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    fn decode_error(bytes: &[u8]) -> String {
+        match decode(bytes) {
+            Err(Error::Decode { message }) => message,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "1+2*3\n5-6";
+        let mut insns = compile(parse(tokenize(source.as_bytes()).unwrap()).unwrap()).unwrap();
+        // the parser can't spell a tree that spills, so a stack slot by hand
+        insns.insert(
+            0,
+            Instruction {
+                opcode: InsnOpcode::Copy,
+                operands: vec![InsnOperand::Stack(3), InsnOperand::Reg(7)],
+                span: None,
+            },
+        );
+
+        let (decoded, text) = decode(&encode(&insns, Some(source)).unwrap()).unwrap();
+        assert_eq!(decoded, insns);
+        assert_eq!(text.as_deref(), Some(source));
+
+        // no spans and no source, no debug info
+        let bare: Vec<Instruction> = insns.into_iter().map(|insn| Instruction { span: None, ..insn }).collect();
+        let bytes = encode(&bare, None).unwrap();
+        assert_eq!(u16::from_le_bytes([bytes[6], bytes[7]]), 0);
+        assert_eq!(decode(&bytes).unwrap(), (bare, None));
+    }
+
+    #[test]
+    fn bad_files() {
+        let bytes = halt();
+        assert!(decode(&bytes).is_ok());
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(decode_error(&resealed(magic)).contains("bad magic number"));
+
+        let mut version = bytes.clone();
+        version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(decode_error(&resealed(version)).contains("Unsupported bytecode version 2"));
+
+        // cut short with a checksum that matches what's left, or without one
+        let mut short = bytes[..bytes.len() - 5].to_vec();
+        short.extend_from_slice(&crc32(&short).to_le_bytes());
+        assert!(decode_error(&short).starts_with("Truncated file"));
+        assert!(decode_error(&bytes[..bytes.len() - 5]).starts_with("Checksum mismatch"));
+        assert!(decode_error(&bytes[..6]).starts_with("Checksum mismatch"));
+        assert!(decode_error(&bytes[..2]).contains("bad magic number"));
+
+        let mut corrupt = bytes.clone();
+        corrupt[bytes.len() - 1] ^= 1;
+        assert!(decode_error(&corrupt).starts_with("Checksum mismatch"));
+        // the damage is never parsed
+        let mut corrupt = bytes.clone();
+        corrupt[16] = 0xff;
+        assert!(decode_error(&corrupt).starts_with("Checksum mismatch"));
+
+        // magic, version, flags, no constants, one instruction, then its opcode
+        let mut opcode = bytes.clone();
+        opcode[16] = OPCODES.len() as u8;
+        assert_eq!(decode_error(&resealed(opcode)), "Unknown opcode 0x0b in instruction 0");
+    }
+
+    #[test]
+    fn spans_outside_the_source() {
+        let spanned = |start: usize, end: usize| {
+            [Instruction {
+                opcode: InsnOpcode::Halt,
+                operands: vec![],
+                span: Some(Span { start, end }),
+            }]
+        };
+
+        // the last span's end is right before the checksum
+        let with_end = |source: &str, end: u32| {
+            let mut bytes = encode(&spanned(0, source.len()), Some(source)).unwrap();
+            let at = bytes.len() - 8;
+            bytes[at..at + 4].copy_from_slice(&end.to_le_bytes());
+            resealed(bytes)
+        };
+
+        assert!(decode(&with_end("1+2", 3)).is_ok());
+        assert_eq!(decode_error(&with_end("1+2", 9)), "Invalid span 0..9 for instruction 0 (3 bytes of source)");
+        // halfway into the 2 bytes of the é
+        assert!(decode_error(&with_end("é", 1)).starts_with("Invalid span 0..1"));
+
+        // nor are they written
+        for (span, source) in [(spanned(2, 9), "1+2"), (spanned(0, 1), "é")] {
+            assert!(matches!(encode(&span, Some(source)), Err(Error::Encode { .. })), "{}", source);
+        }
+        // without a source they're dropped
+        let bytes = encode(&spanned(2, 9), None).unwrap();
+        assert_eq!(u16::from_le_bytes([bytes[6], bytes[7]]), 0);
+        assert_eq!(decode(&bytes).unwrap().0[0].span, None);
+    }
+
+    #[test]
+    fn too_big() {
+        let insn = |operands: Vec<InsnOperand>| Instruction {
+            opcode: InsnOpcode::Push,
+            operands,
+            span: None,
+        };
+
+        let many = insn(vec![InsnOperand::Imm(0); 256]);
+        assert!(matches!(encode(&[many], None), Err(Error::Encode { .. })));
+        let deep = insn(vec![InsnOperand::Stack(u32::MAX as usize + 1)]);
+        assert!(matches!(encode(&[deep], None), Err(Error::Encode { .. })));
+    }
+}
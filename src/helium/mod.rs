@@ -1,2 +1,144 @@
-// Everything needed to compile into Helium Bytecode
+// The Helium bytecode ISA: what instructions look like, what they do, and
+// how they're stored, checked and printed. Front ends only need this module
+// to produce code the vm runs.
+//
+// A machine has a register file (Reg0, Reg1, ..., as many as its Target
+// says), a stack of i32 values that instructions can address by absolute slot
+// (Stack(0) is the bottom), and an output list. Arithmetic is checked:
+// overflow and division by zero stop the program with an error instead of
+// wrapping.
+//
+//   opcode  operands          effect
+//   ------  ----------------  ----------------------------------------------
+//   Ldc     dst, src          dst = src; dst Stack or Reg, src Imm or Stack
+//   Push    src               push src onto the stack; src Imm or Reg
+//   Pop     dst               pop the top of the stack into dst; dst Reg
+//   Copy    dst, src          dst = src; both Stack or Reg
+//   Add     dst, src          dst = dst + src; dst Stack or Reg, src any
+//   Sub     dst, src          dst = dst - src
+//   Mul     dst, src          dst = dst * src
+//   Div     dst, src          dst = dst / src, rounding towards zero
+//   Out     src               append src to the output; src any
+//   Ret     src               stop, the program's result is src
+//   Halt                      stop, the program's result is the output
+//
+// Running past the last instruction is the same as Halt. verify::verify
+// checks the operand kinds above, hbc stores programs as .hbc files, and asm
+// reads and writes them as text.
+pub mod asm;
+pub mod hbc;
+pub mod verify;
 
+use crate::error::Error;
+use crate::token::Span;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InsnOpcode {
+    // data
+    Ldc,
+    Push,
+    Pop,
+    Copy,
+
+    // math
+    Add,
+    Sub,
+    Mul,
+    Div,
+
+    // results
+    Out,
+    Ret,
+    Halt,
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InsnOperand {
+    Imm(i32),
+    Stack(usize),
+    // index into the register file, written Reg0, Reg1, ...
+    Reg(u8),
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub opcode: InsnOpcode,
+    pub operands: Vec<InsnOperand>,
+    // the part of the source this instruction was compiled from, if any
+    pub span: Option<Span>,
+}
+
+// Every opcode, in the order of their numbers in .hbc files
+pub const OPCODES: [InsnOpcode; 11] = [
+    InsnOpcode::Ldc,
+    InsnOpcode::Push,
+    InsnOpcode::Pop,
+    InsnOpcode::Copy,
+    InsnOpcode::Add,
+    InsnOpcode::Sub,
+    InsnOpcode::Mul,
+    InsnOpcode::Div,
+    InsnOpcode::Out,
+    InsnOpcode::Ret,
+    InsnOpcode::Halt,
+];
+
+// The machine code is generated for and run on
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Target {
+    // size of the register file, at most 256 since .hbc stores them in a byte
+    pub registers: usize,
+}
+
+pub const HELIUM: Target = Target { registers: 8 };
+
+impl Target {
+    // Code generators call this before handing out registers: there has to be
+    // one to compute in, and Reg numbers are a byte
+    pub fn check(&self) -> Result<(), Error> {
+        if !(1..=256).contains(&self.registers) {
+            return Err(Error::compile(
+                &format!("A target needs 1 to 256 registers, not {}", self.registers),
+                None,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Target {
+    fn default() -> Target {
+        HELIUM
+    }
+}
+
+impl Display for InsnOperand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsnOperand::Imm(n) => write!(f, "Imm({})", n),
+            InsnOperand::Stack(n) => write!(f, "Stack({})", n),
+            InsnOperand::Reg(n) => write!(f, "Reg{}", n),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Opc OP1, Op2, .. OpN
+        let mut buff = String::new();
+
+        for i in 0 .. self.operands.len() {
+            if i == 0 {
+                buff.push_str(&format!("{}", &self.operands[i]));
+                continue;
+            }
+
+            buff.push_str(&format!(", {}", &self.operands[i]));
+        }
+
+        if buff.is_empty() {
+            return write!(f, "{:?}", self.opcode);
+        }
+        write!(f, "{:?} {}", self.opcode, buff)
+    }
+}
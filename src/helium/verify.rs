@@ -0,0 +1,160 @@
+// Checks a whole program before it runs, so bytecode that would hit an
+// illegal instruction halfway through is rejected up front
+use crate::error::Error;
+use crate::helium::{InsnOpcode, InsnOperand, Instruction, Target};
+
+const IMM: u8 = 1;
+const STACK: u8 = 2;
+const REG: u8 = 4;
+
+// the operand kinds each operand of an opcode may have
+fn signature(opcode: InsnOpcode) -> &'static [u8] {
+    match opcode {
+        InsnOpcode::Ldc => &[STACK | REG, IMM | STACK],
+        InsnOpcode::Push => &[IMM | REG],
+        InsnOpcode::Pop => &[REG],
+        InsnOpcode::Copy => &[STACK | REG, STACK | REG],
+        InsnOpcode::Add | InsnOpcode::Sub | InsnOpcode::Mul | InsnOpcode::Div => {
+            &[STACK | REG, IMM | STACK | REG]
+        }
+        InsnOpcode::Out | InsnOpcode::Ret => &[IMM | STACK | REG],
+        InsnOpcode::Halt => &[],
+    }
+}
+
+fn kind(operand: &InsnOperand) -> u8 {
+    match operand {
+        InsnOperand::Imm(_) => IMM,
+        InsnOperand::Stack(_) => STACK,
+        InsnOperand::Reg(_) => REG,
+    }
+}
+
+fn kind_names(kinds: u8) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    if kinds & IMM != 0 {
+        names.push("Imm");
+    }
+    if kinds & STACK != 0 {
+        names.push("Stack");
+    }
+    if kinds & REG != 0 {
+        names.push("Reg");
+    }
+
+    names.join(" or ")
+}
+
+// Whether a single instruction has the right number and kinds of operands,
+// with registers that exist on target. The vm checks every instruction it runs
+// with this too, so it accepts exactly what the verifier does.
+pub fn check(insn: &Instruction, target: &Target) -> Result<(), String> {
+    let signature = signature(insn.opcode);
+    if insn.operands.len() != signature.len() {
+        return Err(format!(
+            "{:?} takes {} operand(s), got {}",
+            insn.opcode,
+            signature.len(),
+            insn.operands.len()
+        ));
+    }
+
+    for (n, (operand, kinds)) in insn.operands.iter().zip(signature).enumerate() {
+        if kind(operand) & kinds == 0 {
+            return Err(format!(
+                "operand {} of {:?} must be {}, got {}",
+                n + 1,
+                insn.opcode,
+                kind_names(*kinds),
+                operand
+            ));
+        }
+
+        if let InsnOperand::Reg(reg) = operand {
+            if *reg as usize >= target.registers {
+                return Err(format!(
+                    "{} doesn't exist, the target has {} registers",
+                    operand, target.registers
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn verify(insns: &[Instruction], target: &Target) -> Result<(), Error> {
+    // the stack is only ever changed by push and pop, and there are no jumps
+    // yet, so its depth at every instruction is known statically
+    let mut depth: usize = 0;
+
+    for (index, insn) in insns.iter().enumerate() {
+        // the instruction itself is part of the message, the listing may not be at hand
+        let error = |message: String| Error::Verify {
+            message: format!("{}: {}", insn, message),
+            index,
+        };
+        check(insn, target).map_err(error)?;
+
+        for operand in &insn.operands {
+            if let InsnOperand::Stack(slot) = operand {
+                if *slot >= depth {
+                    return Err(error(format!(
+                        "stack slot {} used but only {} pushed",
+                        slot, depth
+                    )));
+                }
+            }
+        }
+
+        match insn.opcode {
+            InsnOpcode::Push => depth += 1,
+            InsnOpcode::Pop => {
+                if depth == 0 {
+                    return Err(error(String::from("pop from an empty stack")));
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+    use crate::helium::HELIUM;
+
+    fn rejected(text: &str) -> (usize, String) {
+        match verify(&assemble(text).unwrap().instructions, &HELIUM) {
+            Err(Error::Verify { index, message }) => (index, message),
+            other => panic!("{}: {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn accepts() {
+        let text = "Push 0\nLdc Reg7, 2\nCopy Stack(0), Reg7\nMul Reg7, Stack(0)\nPop Reg0\nOut Reg7\nHalt";
+        assert!(verify(&assemble(text).unwrap().instructions, &HELIUM).is_ok());
+    }
+
+    #[test]
+    fn rejects() {
+        for (text, index, reason) in [
+            ("Ldc Reg0, 1\nAdd 2, Reg0", 1, "operand 1 of Add must be Stack or Reg, got Imm(2)"),
+            ("Ldc Reg0, 1\nRet Reg0, Reg0", 1, "Ret takes 1 operand(s), got 2"),
+            ("Halt 1", 0, "Halt takes 0 operand(s), got 1"),
+            ("Push 0\nLdc Stack(0), 1\nLdc Stack(1), 1", 2, "stack slot 1 used but only 1 pushed"),
+            ("Push 0\nPop Reg0\nOut Stack(0)", 2, "stack slot 0 used but only 0 pushed"),
+            ("Push 0\nPop Reg0\nPop Reg0", 2, "pop from an empty stack"),
+            ("Ldc Reg8, 1", 0, "Reg8 doesn't exist, the target has 8 registers"),
+        ] {
+            let (at, message) = rejected(text);
+            assert_eq!(at, index, "{}", text);
+            assert!(message.ends_with(reason), "{}: {}", text, message);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+// SSA intermediate representation between the parse tree and Helium bytecode
+//
+// Every value is defined exactly once, by the instruction that computes it,
+// and values are numbered in the order they're defined:
+//
+//   %0 = const 6
+//   %1 = const 7
+//   %2 = mul %0, %1
+//   ret %2
+//
+// There's no control flow yet, so a program is a single block.
+use crate::bytecode::{registers_needed, RegisterAllocation};
+use crate::error::Error;
+use crate::helium::{InsnOpcode, InsnOperand, Instruction, Target};
+use crate::token::{Opcode, Operand, Span, Token};
+use crate::tree::Node;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Var(pub usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    Const(i32),
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Div(Var, Var),
+    Out(Var),
+    Ret(Var),
+    Halt,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    // None for the instructions that don't produce a value
+    pub dst: Option<Var>,
+    pub op: Op,
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Function {
+    pub insts: Vec<Inst>,
+    // the number of the next value to be defined
+    pub vars: usize,
+}
+
+impl Op {
+    // the values this instruction reads
+    pub fn args(&self) -> Vec<Var> {
+        match *self {
+            Op::Const(_) | Op::Halt => vec![],
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b) => vec![a, b],
+            Op::Out(a) | Op::Ret(a) => vec![a],
+        }
+    }
+
+    // Whether the instruction only computes its value from its operands. Only
+    // those can be merged with an identical one or moved around, so anything
+    // with an effect of its own (output, builtins doing I/O, ...) must say no.
+    // Failing on division by zero doesn't count as an effect: the first of two
+    // identical instructions fails the same way before the second is reached.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Op::Const(_) | Op::Add(..) | Op::Sub(..) | Op::Mul(..) | Op::Div(..) => true,
+            Op::Out(_) | Op::Ret(_) | Op::Halt => false,
+        }
+    }
+}
+
+impl Function {
+    // Appends an instruction, giving it a fresh value if it produces one
+    pub fn push(&mut self, op: Op, span: Option<Span>) -> Var {
+        let dst = Var(self.vars);
+        let produces = !matches!(op, Op::Out(_) | Op::Ret(_) | Op::Halt);
+        if produces {
+            self.vars += 1;
+        }
+
+        self.insts.push(Inst {
+            dst: if produces { Some(dst) } else { None },
+            op,
+            span,
+        });
+        dst
+    }
+}
+
+fn build(func: &mut Function, node: &Node<Token>) -> Result<Var, Error> {
+    let token = match &node.value {
+        None => match &node.left {
+            None => return Err(Error::compile("Empty expression", node.span())),
+            Some(left) => return build(func, left),
+        },
+        Some(token) => token,
+    };
+
+    match token.op {
+        Opcode::Const => match token.or {
+            Operand::Int(x) => Ok(func.push(Op::Const(x), Some(token.span))),
+            _ => Err(Error::compile("Invalid operand", node.span())),
+        },
+        Opcode::Operand => {
+            let left_node = match &node.left {
+                None => return Err(Error::compile("No left hand side value for instruction", node.span())),
+                Some(left_node) => left_node,
+            };
+            let right_node = match &node.right {
+                None => return Err(Error::compile("No right hand side value for instruction", node.span())),
+                Some(right_node) => right_node,
+            };
+
+            // the same order the bytecode compiler uses, so lowering in
+            // program order keeps as few values alive at once as it does
+            let left;
+            let right;
+            if registers_needed(right_node) > registers_needed(left_node) {
+                right = build(func, right_node)?;
+                left = build(func, left_node)?;
+            } else {
+                left = build(func, left_node)?;
+                right = build(func, right_node)?;
+            }
+
+            let op = match token.or {
+                Operand::Add => Op::Add(left, right),
+                Operand::Sub => Op::Sub(left, right),
+                Operand::Mul => Op::Mul(left, right),
+                Operand::Div => Op::Div(left, right),
+                _ => return Err(Error::compile("Invalid operand", node.span())),
+            };
+            Ok(func.push(op, node.span()))
+        }
+        Opcode::Separator => Err(Error::compile("Unexpected separator", node.span())),
+    }
+}
+
+// A single expression, whose value the function returns
+pub fn tree_to_ir(tree: &Node<Token>) -> Result<Function, Error> {
+    let mut func = Function::default();
+    let value = build(&mut func, tree)?;
+    func.push(Op::Ret(value), tree.span());
+
+    Ok(func)
+}
+
+// One statement after the other, each of their values written with Out
+pub fn trees_to_ir(trees: &[Node<Token>]) -> Result<Function, Error> {
+    let mut func = Function::default();
+    for tree in trees {
+        let value = build(&mut func, tree)?;
+        func.push(Op::Out(value), tree.span());
+    }
+    func.push(Op::Halt, None);
+
+    Ok(func)
+}
+
+// Lowers to Helium bytecode. Constants aren't given a location of their own,
+// they're used as immediates where an instruction allows it and only loaded
+// with Ldc where it doesn't. A value's location is freed after its last use,
+// and an arithmetic instruction reuses its left operand's location when that
+// was the operand's last use.
+pub fn ir_to_instructions(func: &Function, target: &Target) -> Result<Vec<Instruction>, Error> {
+    target.check()?;
+    let mut constants: HashMap<Var, (i32, Option<Span>)> = HashMap::new();
+    let mut last_use: HashMap<Var, usize> = HashMap::new();
+    for (index, inst) in func.insts.iter().enumerate() {
+        if let (Some(dst), Op::Const(x)) = (inst.dst, inst.op) {
+            constants.insert(dst, (x, inst.span));
+        }
+        for arg in inst.op.args() {
+            last_use.insert(arg, index);
+        }
+    }
+
+    let mut insns: Vec<Instruction> = Vec::new();
+    let mut reg_alloc = RegisterAllocation::new(target);
+    let mut locations: HashMap<Var, InsnOperand> = HashMap::new();
+
+    let location = |locations: &HashMap<Var, InsnOperand>, var: Var| match constants.get(&var) {
+        Some((x, _)) => Ok(InsnOperand::Imm(*x)),
+        None => match locations.get(&var) {
+            None => Err(Error::compile("Value used before it is defined", None)),
+            Some(loc) => Ok(*loc),
+        },
+    };
+
+    for (index, inst) in func.insts.iter().enumerate() {
+        let (opcode, a, b) = match inst.op {
+            Op::Const(_) => continue,
+            Op::Halt => {
+                insns.push(Instruction {
+                    opcode: InsnOpcode::Halt,
+                    operands: vec![],
+                    span: inst.span,
+                });
+                continue;
+            }
+            Op::Out(a) | Op::Ret(a) => {
+                insns.push(Instruction {
+                    opcode: if matches!(inst.op, Op::Out(_)) { InsnOpcode::Out } else { InsnOpcode::Ret },
+                    operands: vec![location(&locations, a)?],
+                    span: inst.span,
+                });
+                if last_use.get(&a) == Some(&index) {
+                    if let Some(loc) = locations.remove(&a) {
+                        reg_alloc.free(loc);
+                    }
+                }
+                continue;
+            }
+            Op::Add(a, b) => (InsnOpcode::Add, a, b),
+            Op::Sub(a, b) => (InsnOpcode::Sub, a, b),
+            Op::Mul(a, b) => (InsnOpcode::Mul, a, b),
+            Op::Div(a, b) => (InsnOpcode::Div, a, b),
+        };
+        let dst = match inst.dst {
+            None => return Err(Error::compile("Arithmetic instruction without a result", inst.span)),
+            Some(dst) => dst,
+        };
+
+        // only the right operand can be an immediate, so a constant on the left
+        // of an operator that commutes is swapped over
+        let (lhs, rhs) = if constants.contains_key(&a)
+            && !constants.contains_key(&b)
+            && matches!(opcode, InsnOpcode::Add | InsnOpcode::Mul)
+        {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        let src = location(&locations, rhs)?;
+
+        let dst_loc = match constants.get(&lhs) {
+            Some((x, span)) => {
+                let loc = reg_alloc.alloc();
+                insns.push(Instruction {
+                    opcode: InsnOpcode::Ldc,
+                    operands: vec![loc, InsnOperand::Imm(*x)],
+                    span: *span,
+                });
+                loc
+            }
+            None if last_use.get(&lhs) == Some(&index) => match locations.remove(&lhs) {
+                None => return Err(Error::compile("Value used before it is defined", inst.span)),
+                Some(loc) => loc,
+            },
+            None => {
+                let loc = reg_alloc.alloc();
+                insns.push(Instruction {
+                    opcode: InsnOpcode::Copy,
+                    operands: vec![loc, location(&locations, lhs)?],
+                    span: inst.span,
+                });
+                loc
+            }
+        };
+
+        insns.push(Instruction {
+            opcode,
+            operands: vec![dst_loc, src],
+            span: inst.span,
+        });
+        // when both operands are the same value its location is dst_loc now
+        if rhs != lhs && last_use.get(&rhs) == Some(&index) {
+            if let Some(loc) = locations.remove(&rhs) {
+                reg_alloc.free(loc);
+            }
+        }
+
+        if last_use.contains_key(&dst) {
+            locations.insert(dst, dst_loc);
+        } else {
+            reg_alloc.free(dst_loc);
+        }
+    }
+    reg_alloc.push_stack_slots(&mut insns);
+
+    Ok(insns)
+}
+
+impl Display for Var {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(dst) = self.dst {
+            write!(f, "{} = ", dst)?;
+        }
+
+        match self.op {
+            Op::Const(x) => write!(f, "const {}", x),
+            Op::Add(a, b) => write!(f, "add {}, {}", a, b),
+            Op::Sub(a, b) => write!(f, "sub {}, {}", a, b),
+            Op::Mul(a, b) => write!(f, "mul {}, {}", a, b),
+            Op::Div(a, b) => write!(f, "div {}, {}", a, b),
+            Op::Out(a) => write!(f, "out {}", a),
+            Op::Ret(a) => write!(f, "ret {}", a),
+            Op::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for inst in &self.insts {
+            writeln!(f, "{}", inst)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::disassemble;
+    use crate::helium::HELIUM;
+    use crate::token::tokenize;
+    use crate::tree::tokens_to_trees;
+
+    fn ir(src: &str) -> Function {
+        let trees = tokens_to_trees(tokenize(src.as_bytes()).unwrap()).unwrap();
+        if trees.len() == 1 {
+            tree_to_ir(&trees[0]).unwrap()
+        } else {
+            trees_to_ir(&trees).unwrap()
+        }
+    }
+
+    #[test]
+    fn dump() {
+        assert_eq!(
+            ir("6*7-2").to_string(),
+            "%0 = const 6\n%1 = const 7\n%2 = const 2\n%3 = sub %1, %2\n%4 = mul %0, %3\nret %4\n"
+        );
+        assert_eq!(ir("1;2").to_string(), "%0 = const 1\nout %0\n%1 = const 2\nout %1\nhalt\n");
+    }
+
+    #[test]
+    fn lowering() {
+        let insns = ir_to_instructions(&ir("6*7-2"), &HELIUM).unwrap();
+        assert_eq!(
+            disassemble(&insns, &HashMap::new()),
+            "0: Ldc Reg0, Imm(7)\n1: Sub Reg0, Imm(2)\n2: Mul Reg0, Imm(6)\n3: Ret Reg0\n"
+        );
+
+        // a value read twice is copied before the first read overwrites it
+        let mut func = Function::default();
+        let x = func.push(Op::Const(3), None);
+        let y = func.push(Op::Sub(x, x), None);
+        let z = func.push(Op::Add(y, y), None);
+        let w = func.push(Op::Mul(z, y), None);
+        func.push(Op::Ret(w), None);
+        assert_eq!(
+            disassemble(&ir_to_instructions(&func, &HELIUM).unwrap(), &HashMap::new()),
+            "0: Ldc Reg0, Imm(3)\n1: Sub Reg0, Imm(3)\n2: Copy Reg1, Reg0\n3: Add Reg1, Reg0\n4: Mul Reg1, Reg0\n5: Ret Reg1\n"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+// A calculator that compiles arithmetic to Helium bytecode and runs it
+//
+// The one-call entry point is eval. Each step of the pipeline is exposed too,
+// for callers that want the tokens, trees or instructions in between:
+//
+//   let tokens = calc::tokenize("1+2;3*4".as_bytes())?;
+//   let trees = calc::parse(tokens)?;
+//   let insns = calc::compile(trees)?;
+//   let value = calc::run(insns)?; // Value::List(vec![3, 12])
+//
+// or, to run it a slice at a time, calc::State::new and State::advance.
+//
+// The functions and types re-exported here are the stable API, the modules
+// behind them may change between versions.
+pub mod bytecode;
+pub mod cse;
+pub mod error;
+pub mod eval;
+pub mod fold;
+#[cfg(test)]
+mod fuzz;
+pub mod helium;
+pub mod ir;
+pub mod peephole;
+pub mod token;
+pub mod tree;
+pub mod vm;
+
+pub use crate::helium::{Instruction, Target};
+pub use crate::error::Error;
+pub use crate::token::{tokenize, Span, Token};
+pub use crate::tree::Node;
+pub use crate::vm::{run, State, Status, Value};
+
+// Splits the tokens into statements, separated by ';' or newlines, and parses each
+pub fn parse(tokens: Vec<Token>) -> Result<Vec<Node<Token>>, Error> {
+    tree::tokens_to_trees(tokens)
+}
+
+// Compiles parsed statements to verified bytecode for the standard Helium
+// machine. A single expression returns its value, so run gives a Value::Int;
+// several statements each output theirs and run gives a Value::List
+pub fn compile(trees: Vec<Node<Token>>) -> Result<Vec<Instruction>, Error> {
+    compile_for(trees, &helium::HELIUM)
+}
+
+// The same for a machine with a different register file, to be run with vm::run_on
+pub fn compile_for(trees: Vec<Node<Token>>, target: &Target) -> Result<Vec<Instruction>, Error> {
+    let mut trees: Vec<Node<Token>> = trees.into_iter().map(fold::fold_constants).collect();
+    let insns = if trees.len() == 1 {
+        bytecode::tree_to_instructions(trees.remove(0), target)?
+    } else {
+        bytecode::trees_to_instructions(trees, target)?
+    };
+
+    let insns = peephole::optimize(insns, &peephole::default_rules());
+    helium::verify::verify(&insns, target)?;
+    Ok(insns)
+}
+
+// The same through the SSA IR, with common subexpressions eliminated
+pub fn compile_via_ir(trees: Vec<Node<Token>>, target: &Target) -> Result<Vec<Instruction>, Error> {
+    lower_ir(&to_ir(trees)?, target)
+}
+
+// The first half of compile_via_ir, for callers that want to look at the IR
+pub fn to_ir(trees: Vec<Node<Token>>) -> Result<ir::Function, Error> {
+    let trees: Vec<Node<Token>> = trees.into_iter().map(fold::fold_constants).collect();
+    let func = if trees.len() == 1 {
+        ir::tree_to_ir(&trees[0])?
+    } else {
+        ir::trees_to_ir(&trees)?
+    };
+
+    Ok(cse::eliminate_common_subexpressions(func))
+}
+
+// And the second: the IR to verified bytecode for target
+pub fn lower_ir(func: &ir::Function, target: &Target) -> Result<Vec<Instruction>, Error> {
+    let insns = peephole::optimize(ir::ir_to_instructions(func, target)?, &peephole::default_rules());
+    helium::verify::verify(&insns, target)?;
+    Ok(insns)
+}
+
+// Tokenizes, parses, compiles and runs source in one go
+pub fn eval(source: &str) -> Result<Value, Error> {
+    run(compile(parse(tokenize(source.as_bytes())?)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_call() {
+        assert_eq!(eval("1+2*3*4*5+10*31-9+2").unwrap(), Value::Int(4024));
+        assert_eq!(eval("1+2;3*4\n5").unwrap(), Value::List(vec![3, 12, 5]));
+        assert!(matches!(eval("7-3/0"), Err(Error::Runtime { .. })));
+        assert!(matches!(eval("1+x"), Err(Error::Tokenize { .. })));
+    }
+
+    #[test]
+    fn via_ir() {
+        let trees = parse(tokenize("1+2*3;4-5*3\n7/2".as_bytes()).unwrap()).unwrap();
+        let target = Target { registers: 2 };
+        let insns = compile_via_ir(trees, &target).unwrap();
+        assert_eq!(vm::run_on(&target, insns).unwrap(), Value::List(vec![7, -11, 3]));
+
+        let one = parse(tokenize("2*3*4".as_bytes()).unwrap()).unwrap();
+        assert_eq!(run(compile_via_ir(one, &helium::HELIUM).unwrap()).unwrap(), Value::Int(24));
+    }
+
+    #[test]
+    fn targets() {
+        let trees = || parse(tokenize("1+2*3".as_bytes()).unwrap()).unwrap();
+        for registers in [0, 257, 1000] {
+            let err = compile_for(trees(), &Target { registers }).unwrap_err();
+            assert!(matches!(err, Error::Compile { .. }), "{}: {:?}", registers, err);
+        }
+        for registers in [1, 256] {
+            let target = Target { registers };
+            assert_eq!(vm::run_on(&target, compile_for(trees(), &target).unwrap()).unwrap(), Value::Int(7));
+        }
+    }
+}
@@ -1,32 +1,211 @@
-mod bytecode;
-mod token;
-mod tree;
-mod vm;
-mod helium;
-
-use crate::bytecode::tree_to_instructions;
-use crate::token::tokenize;
-use crate::tree::tokens_to_tree;
-use crate::vm::run;
-use std::fs::File;
+use calc::eval::eval_tree;
+use calc::helium::{asm, hbc, verify};
+use calc::vm::debugger::Debugger;
+use calc::vm::{run_limited, Limits};
+use calc::vm::profile::Profile;
+use calc::vm::trace::{JsonTrace, NoTrace, Sink, TextTrace};
+use calc::{compile_for, lower_ir, parse, to_ir, tokenize, Error, Instruction, Span, Target};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufWriter;
+use std::process;
 
+// calc [--emit out.hbc] [--ir] [--registers n] [--debug] [--trace none|text|json] [--trace-out file] [--fuel n] [--max-stack n] [--profile counts|time] [input.txt]
+// calc [--emit out.hbc] --asm program.hasm
+// calc [--debug] --run program.hbc
 fn main() {
-    let file = File::open("input.txt").unwrap();
-    let tokens = tokenize(file).unwrap();
-    println!("=== [tokens] ===\n{:#?}", tokens);
-    let tree = tokens_to_tree(tokens).unwrap();
-    println!("=== [parse tree] ===\n{:#?}", tree);
-    let out = tree.convert_dot();
-    println!("=== [out] ===\n{}", out);
+    // the source the instructions were compiled from, for pointing at errors
+    let mut source: Option<String> = None;
+
+    if let Err(err) = cli(&mut source) {
+        println!("=== [error] ===\n{}", err);
+        if let (Some(text), Some(span)) = (&source, err.span()) {
+            print!("{}", point_at(text, span));
+        }
+        if let Error::Limit { partial, .. } = &err {
+            println!("=== [partial state] ===\n{:#?}", partial);
+        }
+        process::exit(1);
+    }
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("usage: calc [--emit out.hbc] [--ir] [--registers n] [--debug] [input.txt]");
+    eprintln!("       calc [--emit out.hbc] --asm program.hasm");
+    eprintln!("       calc [--debug] --run program.hbc");
+    eprintln!("       each also takes [--trace none|text|json] [--trace-out file] [--fuel n] [--max-stack n] [--profile counts|time]");
+    process::exit(2);
+}
+
+fn cli(source: &mut Option<String>) -> Result<(), Error> {
+    let mut input = String::from("input.txt");
+    let mut emit: Option<String> = None;
+    let mut bytecode: Option<String> = None;
+    let mut assembly: Option<String> = None;
+    // compile through the SSA IR instead of straight from the tree
+    let mut via_ir = false;
+    let mut target = Target::default();
+    // step through the program interactively instead of running it
+    let mut debug = false;
+    // how to report every instruction the vm runs, and where to, stdout by default
+    let mut trace = String::from("none");
+    let mut trace_out: Option<String> = None;
+    let mut limits = Limits::default();
+    // count what runs instead of tracing it, and time it with "time"
+    let mut profile: Option<String> = None;
 
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--emit" => emit = Some(args.next().unwrap_or_else(|| usage("--emit needs an output file"))),
+            "--run" => bytecode = Some(args.next().unwrap_or_else(|| usage("--run needs a bytecode file"))),
+            "--asm" => assembly = Some(args.next().unwrap_or_else(|| usage("--asm needs an assembly file"))),
+            "--ir" => via_ir = true,
+            "--debug" => debug = true,
+            "--trace" => match args.next() {
+                Some(kind) if matches!(kind.as_str(), "none" | "text" | "json") => trace = kind,
+                _ => usage("--trace needs one of none, text or json"),
+            },
+            "--trace-out" => trace_out = Some(args.next().unwrap_or_else(|| usage("--trace-out needs an output file"))),
+            "--profile" => match args.next() {
+                Some(kind) if matches!(kind.as_str(), "counts" | "time") => profile = Some(kind),
+                _ => usage("--profile needs counts or time"),
+            },
+            "--fuel" => match args.next().map(|n| n.parse::<u64>()) {
+                Some(Ok(n)) => limits.fuel = Some(n),
+                _ => usage("--fuel needs a number of instructions"),
+            },
+            "--max-stack" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => limits.stack = Some(n),
+                _ => usage("--max-stack needs a number of stack slots"),
+            },
+            "--registers" => {
+                target = match args.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(registers)) => Target { registers },
+                    _ => usage("--registers needs a register count"),
+                };
+                if let Err(err) = target.check() {
+                    usage(&format!("--registers: {}", err.message()));
+                }
+            }
+            _ => input = arg,
+        }
+    }
 
-    let insns = tree_to_instructions(tree).unwrap();
+    let mut labels = HashMap::new();
+    let insns = match (bytecode, assembly) {
+        (Some(path), _) => {
+            let (insns, text) = hbc::decode(&fs::read(path)?)?;
+            *source = text;
+            insns
+        }
+        (None, Some(path)) => {
+            let asm = asm::assemble(&fs::read_to_string(path)?)?;
+            labels = asm.labels;
+            asm.instructions
+        }
+        (None, None) => {
+            *source = Some(fs::read_to_string(&input)?);
+            compile_source(source.as_deref().unwrap_or(""), via_ir, &target)?
+        }
+    };
 
     println!("=== [instructions] ===");
-    for (index, insn) in insns.iter().enumerate() {
-        println!("{}: {}",index, insn);
+    print!("{}", asm::disassemble(&insns, &labels));
+
+    if let Some(text) = source {
+        println!("=== [source map] ===");
+        for (index, insn) in insns.iter().enumerate() {
+            if let Some(span) = insn.span {
+                // spans of a program handed over from elsewhere may not fit the source
+                let code = text.get(span.start..span.end).unwrap_or("?");
+                println!("{}: {}..{} `{}`", index, span.start, span.end, code);
+            }
+        }
+    }
+
+    if let Some(path) = emit {
+        fs::write(&path, hbc::encode(&insns, source.as_deref())?)?;
+        println!("=== [emitted {}] ===", path);
     }
+
+    verify::verify(&insns, &target)?;
+    if debug {
+        println!("=== [debugger] ===");
+        return Debugger::new(&target, insns, source.clone()).repl(io::stdin().lock(), io::stdout());
+    }
+
     println!("=== [vm] ===");
 
-    println!("final vm state: {:#?}", run(insns).unwrap().debug());
+    if let Some(kind) = profile {
+        if trace != "none" {
+            usage("--profile can't be combined with --trace");
+        }
+        let mut profile = if kind == "time" { Profile::timed() } else { Profile::new() };
+        let value = run_limited(&target, insns.clone(), &limits, &mut profile)?;
+        println!("=== [profile] ===\n{}", profile.report(&insns, source.as_deref()));
+        println!("=== [result] ===\n{:?}", value);
+        return Ok(());
+    }
+
+    let out: Box<dyn io::Write> = match trace_out {
+        Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    let mut sink: Box<dyn Sink> = match trace.as_str() {
+        "text" => Box::new(TextTrace(out)),
+        "json" => Box::new(JsonTrace(out)),
+        _ => Box::new(NoTrace),
+    };
+
+    let value = run_limited(&target, insns, &limits, sink.as_mut())?;
+    println!("=== [result] ===\n{:?}", value);
+    Ok(())
+}
+
+// The source line span is on, with the span underlined. Nothing for a span
+// that doesn't start in the source
+fn point_at(source: &str, span: Span) -> String {
+    if source.get(..span.start).is_none() {
+        return String::new();
+    }
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..].find('\n').map_or(source.len(), |i| span.start + i);
+    let line_no = source[..line_start].matches('\n').count() + 1;
+    let prefix = format!("{} | ", line_no);
+    // in characters, so the carets line up under text that isn't ASCII
+    let column = source[line_start..span.start].chars().count();
+    let width = source.get(span.start..span.end.min(line_end)).map_or(1, |text| text.chars().count());
+
+    format!(
+        "{}{}\n{}{}\n",
+        prefix,
+        &source[line_start..line_end],
+        " ".repeat(prefix.len() + column),
+        "^".repeat(width.max(1))
+    )
+}
+
+// The library's pipeline, printing every stage along the way
+fn compile_source(source: &str, via_ir: bool, target: &Target) -> Result<Vec<Instruction>, Error> {
+    let tokens = tokenize(source.as_bytes())?;
+    println!("=== [tokens] ===\n{:#?}", tokens);
+    let trees = parse(tokens)?;
+    println!("=== [parse tree] ===\n{:#?}", trees);
+    for tree in &trees {
+        let out = tree.convert_dot();
+        println!("=== [out] ===\n{}", out);
+        println!("=== [eval] ===\n{:?}", eval_tree(tree));
+    }
+
+    if !via_ir {
+        return compile_for(trees, target);
+    }
+
+    let func = to_ir(trees)?;
+    println!("=== [ir] ===\n{}", func);
+    lower_ir(&func, target)
 }
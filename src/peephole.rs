@@ -0,0 +1,433 @@
+// Peephole optimizer: rules look at the instructions around a position and
+// replace a short run of them with something cheaper
+use crate::helium::{InsnOpcode, InsnOperand, Instruction};
+use crate::token::Operand;
+
+pub struct Rewrite {
+    // how many instructions starting at the position get replaced
+    pub len: usize,
+    pub replacement: Vec<Instruction>,
+}
+
+pub trait Rule {
+    // Tries to rewrite the instructions starting at insns[at]
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite>;
+}
+
+pub fn default_rules() -> Vec<Box<dyn Rule>> {
+    vec![
+        Box::new(CopySelf),
+        Box::new(LdcCopy),
+        Box::new(CommuteIntoCopy),
+        Box::new(PushOverwrite),
+        Box::new(FoldLdc),
+        Box::new(InlineConstant),
+    ]
+}
+
+// Applies the rules until none of them matches anywhere anymore
+pub fn optimize(mut insns: Vec<Instruction>, rules: &[Box<dyn Rule>]) -> Vec<Instruction> {
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+        let mut at = 0;
+
+        while at < insns.len() {
+            match rules.iter().find_map(|rule| rule.apply(&insns, at)) {
+                None => at += 1,
+                Some(rewrite) => {
+                    insns.splice(at..at + rewrite.len, rewrite.replacement);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    insns
+}
+
+// which operands an instruction reads, and which one it writes. optimize runs
+// before the verifier, so the operands may not be what the opcode takes
+fn reads(insn: &Instruction) -> &[InsnOperand] {
+    match insn.opcode {
+        InsnOpcode::Ldc | InsnOpcode::Copy => insn.operands.get(1..).unwrap_or(&[]),
+        InsnOpcode::Push => &insn.operands[..],
+        InsnOpcode::Pop | InsnOpcode::Halt => &[],
+        InsnOpcode::Add | InsnOpcode::Sub | InsnOpcode::Mul | InsnOpcode::Div => &insn.operands[..],
+        InsnOpcode::Out | InsnOpcode::Ret => &insn.operands[..],
+    }
+}
+
+fn writes(insn: &Instruction) -> Option<InsnOperand> {
+    match insn.opcode {
+        InsnOpcode::Push | InsnOpcode::Out | InsnOpcode::Ret | InsnOpcode::Halt => None,
+        _ => insn.operands.first().copied(),
+    }
+}
+
+// Whether the value in operand is never read again from instruction `from` on
+fn is_dead(insns: &[Instruction], from: usize, operand: InsnOperand) -> bool {
+    for insn in &insns[from..] {
+        if reads(insn).contains(&operand) {
+            return false;
+        }
+        // popping moves the stack around under absolute slot numbers
+        if insn.opcode == InsnOpcode::Pop && matches!(operand, InsnOperand::Stack(_)) {
+            return false;
+        }
+        if writes(insn) == Some(operand) {
+            return true;
+        }
+    }
+
+    // results only ever leave the program through Out and Ret
+    true
+}
+
+fn stack_depth(insns: &[Instruction]) -> usize {
+    insns.iter().fold(0, |depth, insn| match insn.opcode {
+        InsnOpcode::Push => depth + 1,
+        InsnOpcode::Pop => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+// Copy x, x
+pub struct CopySelf;
+
+impl Rule for CopySelf {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let insn = &insns[at];
+        if insn.opcode == InsnOpcode::Copy && insn.operands.len() == 2 && insn.operands[0] == insn.operands[1] {
+            return Some(Rewrite {
+                len: 1,
+                replacement: vec![],
+            });
+        }
+
+        None
+    }
+}
+
+// Ldc a, x; Copy b, a -> Ldc b, x when a isn't read afterwards
+pub struct LdcCopy;
+
+impl Rule for LdcCopy {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let ldc = &insns[at];
+        let copy = insns.get(at + 1)?;
+        if ldc.opcode != InsnOpcode::Ldc || copy.opcode != InsnOpcode::Copy {
+            return None;
+        }
+        if ldc.operands.len() != 2 || copy.operands.len() != 2 || copy.operands[1] != ldc.operands[0] {
+            return None;
+        }
+        if !is_dead(insns, at + 2, ldc.operands[0]) {
+            return None;
+        }
+
+        Some(Rewrite {
+            len: 2,
+            replacement: vec![Instruction {
+                opcode: InsnOpcode::Ldc,
+                operands: vec![copy.operands[0], ldc.operands[1]],
+                span: ldc.span,
+            }],
+        })
+    }
+}
+
+// Add a, b; Copy b, a -> Add b, a when a isn't read afterwards (same for Mul)
+pub struct CommuteIntoCopy;
+
+impl Rule for CommuteIntoCopy {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let op = &insns[at];
+        let copy = insns.get(at + 1)?;
+        if !matches!(op.opcode, InsnOpcode::Add | InsnOpcode::Mul) || copy.opcode != InsnOpcode::Copy {
+            return None;
+        }
+        if op.operands.len() != 2 || copy.operands.len() != 2 {
+            return None;
+        }
+        if copy.operands[0] != op.operands[1] || copy.operands[1] != op.operands[0] {
+            return None;
+        }
+        if !is_dead(insns, at + 2, op.operands[0]) {
+            return None;
+        }
+
+        Some(Rewrite {
+            len: 2,
+            replacement: vec![Instruction {
+                opcode: op.opcode,
+                operands: vec![op.operands[1], op.operands[0]],
+                span: op.span,
+            }],
+        })
+    }
+}
+
+// Push Imm(0) for a slot whose first use overwrites it: with a constant the
+// constant is pushed right away, with a register only if it comes right after
+pub struct PushOverwrite;
+
+impl Rule for PushOverwrite {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let push = &insns[at];
+        if push.opcode != InsnOpcode::Push || push.operands.first() != Some(&InsnOperand::Imm(0)) {
+            return None;
+        }
+        let slot = InsnOperand::Stack(stack_depth(&insns[..at]));
+
+        for (offset, insn) in insns[at + 1..].iter().enumerate() {
+            if insn.opcode == InsnOpcode::Pop || reads(insn).contains(&slot) {
+                return None;
+            }
+            if writes(insn) != Some(slot) {
+                continue;
+            }
+
+            let src = *insn.operands.get(1)?;
+            let hoistable = match (insn.opcode, src) {
+                (InsnOpcode::Ldc, InsnOperand::Imm(_)) => true,
+                (InsnOpcode::Copy, InsnOperand::Stack(_)) => false,
+                (InsnOpcode::Copy, _) => offset == 0,
+                _ => false,
+            };
+            if !hoistable {
+                return None;
+            }
+
+            let mut replacement = vec![Instruction {
+                opcode: InsnOpcode::Push,
+                operands: vec![src],
+                span: insn.span,
+            }];
+            replacement.extend_from_slice(&insns[at + 1..at + 1 + offset]);
+
+            return Some(Rewrite {
+                len: offset + 2,
+                replacement,
+            });
+        }
+
+        None
+    }
+}
+
+// Ldc a, Imm(x); Add a, Imm(y) -> Ldc a, Imm(x + y), same for the others.
+// What would fail at runtime is left to fail there
+pub struct FoldLdc;
+
+impl Rule for FoldLdc {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let ldc = &insns[at];
+        let op = insns.get(at + 1)?;
+        let operand = match op.opcode {
+            InsnOpcode::Add => Operand::Add,
+            InsnOpcode::Sub => Operand::Sub,
+            InsnOpcode::Mul => Operand::Mul,
+            InsnOpcode::Div => Operand::Div,
+            _ => return None,
+        };
+        if ldc.opcode != InsnOpcode::Ldc || ldc.operands.len() != 2 || op.operands.len() != 2 {
+            return None;
+        }
+        let (InsnOperand::Imm(lhs), InsnOperand::Imm(rhs)) = (ldc.operands[1], op.operands[1]) else {
+            return None;
+        };
+        if op.operands[0] != ldc.operands[0] {
+            return None;
+        }
+
+        Some(Rewrite {
+            len: 2,
+            replacement: vec![Instruction {
+                opcode: InsnOpcode::Ldc,
+                operands: vec![ldc.operands[0], InsnOperand::Imm(operand.apply(lhs, rhs).ok()?)],
+                span: op.span,
+            }],
+        })
+    }
+}
+
+// Ldc a, Imm(x); Sub b, a -> Sub b, Imm(x) when the register a isn't read
+// afterwards, for every instruction that takes an immediate where it reads a.
+// Instructions that don't touch a can sit in between
+pub struct InlineConstant;
+
+impl Rule for InlineConstant {
+    fn apply(&self, insns: &[Instruction], at: usize) -> Option<Rewrite> {
+        let ldc = &insns[at];
+        if ldc.opcode != InsnOpcode::Ldc || ldc.operands.len() != 2 {
+            return None;
+        }
+        let (reg @ InsnOperand::Reg(_), constant @ InsnOperand::Imm(_)) = (ldc.operands[0], ldc.operands[1]) else {
+            return None;
+        };
+        let offset = insns[at + 1..]
+            .iter()
+            .position(|insn| reads(insn).contains(&reg) || writes(insn) == Some(reg))?;
+        let use_at = at + 1 + offset;
+        let next = &insns[use_at];
+
+        let src = match next.opcode {
+            InsnOpcode::Add | InsnOpcode::Sub | InsnOpcode::Mul | InsnOpcode::Div => 1,
+            InsnOpcode::Push | InsnOpcode::Out | InsnOpcode::Ret => 0,
+            _ => return None,
+        };
+        // read anywhere else, say as the destination, it has to stay
+        if next.operands.len() != src + 1 || next.operands[src] != reg || next.operands[..src].contains(&reg) {
+            return None;
+        }
+        if !is_dead(insns, use_at + 1, reg) {
+            return None;
+        }
+
+        let mut operands = next.operands.clone();
+        operands[src] = constant;
+        let mut replacement = insns[at + 1..use_at].to_vec();
+        replacement.push(Instruction {
+            opcode: next.opcode,
+            operands,
+            span: next.span,
+        });
+
+        Some(Rewrite {
+            len: offset + 2,
+            replacement,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::tree_to_instructions;
+    use crate::fuzz::{Rng, Tree};
+    use crate::helium::asm::{assemble, disassemble};
+    use crate::helium::HELIUM;
+    use crate::ir::{ir_to_instructions, tree_to_ir};
+    use crate::token::tokenize;
+    use crate::tree::tokens_to_tree;
+    use crate::vm::run;
+    use std::collections::HashMap;
+
+    fn optimize_asm(text: &str) -> String {
+        let insns = assemble(text).unwrap().instructions;
+        disassemble(&optimize(insns, &default_rules()), &HashMap::new())
+    }
+
+    // Compiled without constant folding, which would leave nothing to optimize,
+    // both straight from the tree and through the IR
+    fn assert_shrinks(src: &str, before: usize, after: usize) {
+        let tree = tokens_to_tree(tokenize(src.as_bytes()).unwrap()).unwrap();
+        let direct = tree_to_instructions(tree.clone(), &HELIUM).unwrap();
+        let ir = ir_to_instructions(&tree_to_ir(&tree).unwrap(), &HELIUM).unwrap();
+
+        for insns in [direct, ir] {
+            let optimized = optimize(insns.clone(), &default_rules());
+            assert_eq!((insns.len(), optimized.len()), (before, after), "{}", src);
+            assert_eq!(run(insns).ok(), run(optimized).ok(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        // left for the verifier to reject
+        let text = "Ldc Reg0, 1\nCopy Reg1, Reg0\nCopy\nPush 0\nLdc Stack(0)\nAdd Reg0";
+        let expected = "0: Ldc Reg0, Imm(1)\n1: Copy Reg1, Reg0\n2: Copy\n3: Push Imm(0)\n4: Ldc Stack(0)\n5: Add Reg0\n";
+        assert_eq!(optimize_asm(text), expected);
+    }
+
+    #[test]
+    fn copy_self() {
+        assert_eq!(optimize_asm("Ldc Reg0, 1\nCopy Reg0, Reg0"), "0: Ldc Reg0, Imm(1)\n");
+    }
+
+    #[test]
+    fn ldc_copy() {
+        assert_eq!(optimize_asm("Ldc Reg3, 1\nCopy Reg0, Reg3"), "0: Ldc Reg0, Imm(1)\n");
+        // reg3 is still read afterwards
+        assert_eq!(
+            optimize_asm("Ldc Reg3, 1\nCopy Reg0, Reg3\nAdd Reg0, Reg3"),
+            "0: Ldc Reg3, Imm(1)\n1: Copy Reg0, Reg3\n2: Add Reg0, Reg3\n"
+        );
+    }
+
+    #[test]
+    fn commute_into_copy() {
+        assert_eq!(
+            optimize_asm("Mul Reg1, Reg0\nCopy Reg0, Reg1"),
+            "0: Mul Reg0, Reg1\n"
+        );
+        // not for sub
+        assert_eq!(
+            optimize_asm("Sub Reg1, Reg0\nCopy Reg0, Reg1"),
+            "0: Sub Reg1, Reg0\n1: Copy Reg0, Reg1\n"
+        );
+    }
+
+    #[test]
+    fn push_overwrite() {
+        assert_eq!(
+            optimize_asm("Push 0\nPush 0\nLdc Reg0, 1\nLdc Stack(1), 5\nCopy Stack(0), Reg0\nRet Reg0"),
+            "0: Push Imm(0)\n1: Push Imm(5)\n2: Ldc Reg0, Imm(1)\n3: Copy Stack(0), Reg0\n4: Ret Reg0\n"
+        );
+        assert_eq!(optimize_asm("Push 0\nCopy Stack(0), Reg2"), "0: Push Reg2\n");
+    }
+
+    #[test]
+    fn fold_ldc() {
+        assert_eq!(
+            optimize_asm("Ldc Reg0, 6\nDiv Reg0, 2\nOut Reg0\nOut Reg0"),
+            "0: Ldc Reg0, Imm(3)\n1: Out Reg0\n2: Out Reg0\n"
+        );
+        // left for the vm to report
+        assert_eq!(
+            optimize_asm("Ldc Reg0, 6\nDiv Reg0, 0\nOut Reg0"),
+            "0: Ldc Reg0, Imm(6)\n1: Div Reg0, Imm(0)\n2: Out Reg0\n"
+        );
+    }
+
+    #[test]
+    fn inline_constant() {
+        assert_eq!(optimize_asm("Ldc Reg1, 2\nSub Reg0, Reg1"), "0: Sub Reg0, Imm(2)\n");
+        assert_eq!(optimize_asm("Ldc Reg1, 2\nOut Reg0\nRet Reg1"), "0: Out Reg0\n1: Ret Imm(2)\n");
+        // reg1 is read again, or is what's written
+        assert_eq!(
+            optimize_asm("Ldc Reg1, 2\nSub Reg0, Reg1\nOut Reg1"),
+            "0: Ldc Reg1, Imm(2)\n1: Sub Reg0, Reg1\n2: Out Reg1\n"
+        );
+        assert_eq!(
+            optimize_asm("Ldc Reg1, 2\nSub Reg1, Reg1\nOut Reg1"),
+            "0: Ldc Reg1, Imm(2)\n1: Sub Reg1, Reg1\n2: Out Reg1\n"
+        );
+    }
+
+    #[test]
+    fn shrinks_real_expressions() {
+        assert_shrinks("1+2*3", 4, 1);
+        assert_shrinks("1+2*3*4*5+10*31-9+2", 10, 1);
+        assert_shrinks("8-2-1", 5, 1);
+        // everything depends on a division that has to stay to fail
+        assert_shrinks("5-1/0", 5, 5);
+    }
+
+    #[test]
+    fn shrinks_spilling_expressions() {
+        // full trees of depth 9 need nine registers, so some values live on the stack
+        let mut rng = Rng::new(7);
+
+        for _ in 0..20 {
+            let insns = tree_to_instructions(Tree::generate(&mut rng, 9).0, &HELIUM).unwrap();
+            let optimized = optimize(insns.clone(), &default_rules());
+
+            assert!(optimized.len() < insns.len());
+            // spans move around, so only the values are compared
+            assert_eq!(run(insns).ok(), run(optimized).ok());
+        }
+    }
+}
@@ -1,10 +1,12 @@
-use std::fs::File;
-use std::io::Read;
+use crate::error::Error;
+use std::io::{ErrorKind, Read};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Opcode {
     Const,
     Operand,
+    // ends a statement
+    Separator,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -14,24 +16,95 @@ pub enum Operand {
     Sub,
     Mul,
     Div,
+    End,
+}
+
+// Byte offsets into the source, end exclusive
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // the smallest span covering both
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Token {
     pub op: Opcode,
     pub or: Operand,
+    pub span: Span,
+}
+
+impl Operand {
+    // Applies an arithmetic operand the same way the vm does, so errors like
+    // division by zero or overflow are reported instead of panicking
+    pub fn apply(self, lhs: i32, rhs: i32) -> Result<i32, &'static str> {
+        let res = match self {
+            Operand::Add => lhs.checked_add(rhs),
+            Operand::Sub => lhs.checked_sub(rhs),
+            Operand::Mul => lhs.checked_mul(rhs),
+            Operand::Div => {
+                if rhs == 0 {
+                    return Err("Division by zero");
+                }
+                lhs.checked_div(rhs)
+            }
+            Operand::Int(_) | Operand::End => return Err("Invalid operand"),
+        };
+
+        match res {
+            None => Err("Arithmetic overflow"),
+            Some(v) => Ok(v),
+        }
+    }
+}
+
+// The error for a character the tokenizer doesn't know, which starts with
+// first. The rest of a multi-byte character is read so the message names it
+// and the span covers it, input that isn't UTF-8 is reported as the byte
+fn unexpected<R: Read>(first: u8, file: &mut R, pos: usize) -> Error {
+    let len = match first {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    };
+    let mut bytes = vec![first];
+    let mut next = [0u8];
+    while bytes.len() < len && file.read_exact(&mut next).is_ok() {
+        bytes.push(next[0]);
+    }
+
+    let (message, end) = match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+        Some(c) => (format!("Unexpected character: {:?}", c), pos + bytes.len()),
+        None => (format!("Unexpected byte {:#04x}, the input isn't UTF-8", first), pos + 1),
+    };
+    Error::Tokenize {
+        message,
+        span: Span { start: pos, end },
+    }
 }
 
-pub fn tokenize(mut file: File) -> Result<Vec<Token>, &'static str> {
+pub fn tokenize<R: Read>(mut file: R) -> Result<Vec<Token>, Error> {
     let mut tokens: Vec<Token> = Vec::new();
     let mut buf: Vec<u8> = vec![0; 1];
 
     let mut res = file.read_exact(&mut buf);
     let mut num_buff = String::new();
     let mut reading_num = false;
+    // offset of the character in buf
+    let mut pos: usize = 0;
     while res.is_ok() {
         let c = buf[0] as char;
-        if reading_num && (c < '0' || c > '9') {
+        if reading_num && !c.is_ascii_digit() {
             reading_num = false;
 
             match num_buff.parse::<i32>() {
@@ -39,10 +112,20 @@ pub fn tokenize(mut file: File) -> Result<Vec<Token>, &'static str> {
                     tokens.push(Token {
                         op: Opcode::Const,
                         or: Operand::Int(num),
+                        span: Span {
+                            start: pos - num_buff.len(),
+                            end: pos,
+                        },
                     });
                 }
                 Err(_) => {
-                    return Err("Could not parse number");
+                    return Err(Error::Tokenize {
+                        message: format!("Could not parse number {}", num_buff),
+                        span: Span {
+                            start: pos - num_buff.len(),
+                            end: pos,
+                        },
+                    });
                 }
             }
 
@@ -53,36 +136,67 @@ pub fn tokenize(mut file: File) -> Result<Vec<Token>, &'static str> {
                 tokens.push(Token {
                     op: Opcode::Operand,
                     or: Operand::Add,
+                    span: Span {
+                        start: pos,
+                        end: pos + 1,
+                    },
                 });
             }
             '-' => {
                 tokens.push(Token {
                     op: Opcode::Operand,
                     or: Operand::Sub,
+                    span: Span {
+                        start: pos,
+                        end: pos + 1,
+                    },
                 });
             }
             '*' => {
                 tokens.push(Token {
                     op: Opcode::Operand,
                     or: Operand::Mul,
+                    span: Span {
+                        start: pos,
+                        end: pos + 1,
+                    },
                 });
             }
             '/' => {
                 tokens.push(Token {
                     op: Opcode::Operand,
                     or: Operand::Div,
+                    span: Span {
+                        start: pos,
+                        end: pos + 1,
+                    },
+                });
+            }
+            ';' | '\n' => {
+                tokens.push(Token {
+                    op: Opcode::Separator,
+                    or: Operand::End,
+                    span: Span {
+                        start: pos,
+                        end: pos + 1,
+                    },
                 });
             }
             '0'..='9' => {
                 num_buff.push(c);
                 reading_num = true;
             }
-            _ => {
-                panic!("Unexpected character: {}", c)
-            }
+            _ => return Err(unexpected(buf[0], &mut file, pos)),
         }
 
         res = file.read_exact(&mut buf);
+        pos += 1;
+    }
+    // running out of input is how the loop ends, anything else is a real error
+    if let Err(err) = res {
+        if err.kind() != ErrorKind::UnexpectedEof {
+            return Err(Error::Io(err));
+        }
     }
 
     if reading_num {
@@ -91,13 +205,45 @@ pub fn tokenize(mut file: File) -> Result<Vec<Token>, &'static str> {
                 tokens.push(Token {
                     op: Opcode::Const,
                     or: Operand::Int(num),
+                    span: Span {
+                        start: pos - num_buff.len(),
+                        end: pos,
+                    },
                 });
             }
             Err(_) => {
-                return Err("Could not parse number");
+                return Err(Error::Tokenize {
+                    message: format!("Could not parse number {}", num_buff),
+                    span: Span {
+                        start: pos - num_buff.len(),
+                        end: pos,
+                    },
+                });
             }
         }
     }
 
     Ok(tokens)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unexpected(input: &[u8]) -> (String, usize, usize) {
+        match tokenize(input) {
+            Err(Error::Tokenize { message, span }) => (message, span.start, span.end),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        assert_eq!(unexpected(b"1+x"), (String::from("Unexpected character: 'x'"), 2, 3));
+        // the whole character, not its first byte
+        assert_eq!(unexpected("1+é".as_bytes()), (String::from("Unexpected character: 'é'"), 2, 4));
+        assert_eq!(unexpected("€2".as_bytes()), (String::from("Unexpected character: '€'"), 0, 3));
+        assert_eq!(unexpected(b"1\xff2"), (String::from("Unexpected byte 0xff, the input isn't UTF-8"), 1, 2));
+        assert_eq!(unexpected(b"1\xc3"), (String::from("Unexpected byte 0xc3, the input isn't UTF-8"), 1, 2));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::token::{Opcode, Token};
+use crate::error::Error;
+use crate::token::{Opcode, Operand, Span, Token};
 
 #[derive(Debug, Clone)]
 pub struct Node<T> {
@@ -7,7 +8,11 @@ pub struct Node<T> {
     pub right: Option<Box<Node<T>>>,
 }
 
-pub fn tokens_to_tree(tokens: Vec<Token>) -> Result<Node<Token>, String> {
+// The first operator becomes the root, and every later one takes the right side
+// of the one before it. A lone number has no operator, so it comes back as a
+// root without a value with the number on its left
+pub fn tokens_to_tree(tokens: Vec<Token>) -> Result<Node<Token>, Error> {
+    check_arity(&tokens)?;
     let mut tree = Node {
         value: None,
         left: None,
@@ -44,7 +49,10 @@ pub fn tokens_to_tree(tokens: Vec<Token>) -> Result<Node<Token>, String> {
                             }
                             Some(right_right) => match right_right.value.as_ref() {
                                 None => {
-                                    return Err(format!("No value for {:#?}", right_right.value))
+                                    return Err(Error::Parse {
+                                        message: String::from("Operator without a value"),
+                                        span: right_right.span(),
+                                    })
                                 }
                                 Some(right_token) => {
                                     if right_token.op == Opcode::Operand {
@@ -68,13 +76,83 @@ pub fn tokens_to_tree(tokens: Vec<Token>) -> Result<Node<Token>, String> {
                     }));
                 }
             }
+            Opcode::Separator => {
+                return Err(Error::Parse {
+                    message: String::from("Unexpected separator inside an expression"),
+                    span: Some(token.span),
+                })
+            }
         }
     }
 
     Ok(tree)
 }
 
+// Numbers and operators have to take turns, starting and ending with a number.
+// The tree doesn't record which side of an operator a number was on, so "-5"
+// would otherwise come out the same as "5-"
+fn check_arity(tokens: &[Token]) -> Result<(), Error> {
+    let error = |message: &str, token: &Token| Error::Parse {
+        message: String::from(message),
+        span: Some(token.span),
+    };
+    let mut after_number = false;
+
+    for token in tokens {
+        match token.op {
+            Opcode::Const if after_number => return Err(error("Number without an operator before it", token)),
+            Opcode::Operand if !after_number => return Err(error("Operator without a left hand side value", token)),
+            Opcode::Const => after_number = true,
+            Opcode::Operand => after_number = false,
+            // reported by the parser itself
+            Opcode::Separator => {}
+        }
+    }
+
+    match tokens.last() {
+        Some(last) if last.op == Opcode::Operand => Err(error("Operator without a right hand side value", last)),
+        _ => Ok(()),
+    }
+}
+
+// Splits the tokens into statements and parses each of them, skipping empty ones
+pub fn tokens_to_trees(tokens: Vec<Token>) -> Result<Vec<Node<Token>>, Error> {
+    let mut trees: Vec<Node<Token>> = Vec::new();
+
+    for statement in tokens.split(|token| token.op == Opcode::Separator) {
+        if !statement.is_empty() {
+            trees.push(tokens_to_tree(statement.to_vec())?);
+        }
+    }
+
+    Ok(trees)
+}
+
 impl Node<Token> {
+    // The part of the source this node and everything below it came from
+    pub fn span(&self) -> Option<Span> {
+        let mut span = self.value.as_ref().map(|token| token.span);
+
+        for child in [&self.left, &self.right].into_iter().flatten() {
+            if let Some(child_span) = child.span() {
+                span = Some(match span {
+                    None => child_span,
+                    Some(span) => span.to(child_span),
+                });
+            }
+        }
+
+        span
+    }
+
+    // The number this node is, if it's a constant
+    pub fn constant(&self) -> Option<i32> {
+        match self.value.as_ref()?.or {
+            Operand::Int(x) => Some(x),
+            _ => None,
+        }
+    }
+
     pub fn convert_dot(&self) -> String {
         let mut buff = String::from("graph G {\n    n0 [shape=Mdiamond];\n    n0 [label=\"start\"];\n");
         let mut last_id = 0;
@@ -86,11 +164,18 @@ impl Node<Token> {
     }
 
     fn convert_dot_inner(&self, buff: &mut String, last_id: &mut i32, id_above: i32) {
+        // the root of a lone number, which hangs right off the start
+        let Some(value) = &self.value else {
+            for child in [&self.right, &self.left].into_iter().flatten() {
+                child.convert_dot_inner(buff, last_id, id_above);
+            }
+            return;
+        };
         let my_id = *last_id + 1;
         *last_id += 1;
 
         buff.push_str(&format!("    n{} -- n{}; \n", id_above, my_id));
-        buff.push_str(&format!("    n{} [label=\"{:?}\"];\n", my_id, self.value.as_ref().unwrap().or));
+        buff.push_str(&format!("    n{} [label=\"{:?}\"];\n", my_id, value.or));
 
         if let Some(right) = &self.right {
             right.convert_dot_inner(buff, last_id, my_id);
@@ -99,4 +184,43 @@ impl Node<Token> {
             left.convert_dot_inner(buff, last_id, my_id);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokenize;
+
+    fn tree(source: &str) -> Result<Node<Token>, Error> {
+        tokens_to_tree(tokenize(source.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn arity() {
+        for (source, message, start) in [
+            ("1+", "Operator without a right hand side value", 1),
+            ("+", "Operator without a left hand side value", 0),
+            ("-5", "Operator without a left hand side value", 0),
+            ("1+*2", "Operator without a left hand side value", 2),
+        ] {
+            match tree(source) {
+                Err(Error::Parse { message: got, span }) => {
+                    assert_eq!((got.as_str(), span.map(|span| span.start)), (message, Some(start)), "{}", source)
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+            assert!(matches!(crate::eval(source), Err(Error::Parse { .. })), "{}", source);
+        }
+
+        assert!(tree("1+2*3").is_ok());
+        assert!(tree("").is_ok());
+    }
+
+    #[test]
+    fn dot() {
+        let lone = tree("5").unwrap().convert_dot();
+        assert!(lone.contains("    n0 -- n1; \n    n1 [label=\"Int(5)\"];\n"), "{}", lone);
+
+        let sum = tree("1+2").unwrap().convert_dot();
+        assert_eq!(sum.matches(" -- ").count(), 3, "{}", sum);
+    }
+}
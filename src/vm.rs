@@ -1,377 +1,345 @@
-use crate::bytecode::{InsnOpcode, InsnOperand, Instruction};
+use crate::error::Error;
+use crate::helium::{InsnOpcode, InsnOperand, Instruction, Target, HELIUM};
+use crate::token::Operand;
+use crate::vm::compact::{decode, Dst, Op, Src};
+use crate::vm::trace::{Event, NoTrace, Sink, Written};
 use std::fmt;
 
+pub mod compact;
+pub mod debugger;
+pub mod history;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
+
 #[derive(Debug)]
 pub struct State {
     i: usize,
     instructions: Vec<Instruction>,
-    reg0: i32,
-    reg1: i32,
-    reg2: i32,
-    reg3: i32,
-    reg4: i32,
-    reg5: i32,
-    reg6: i32,
-    reg7: i32,
+    // instructions decoded for running, or why they can't be, reported if they're reached
+    code: Vec<Result<Op, String>>,
+    // the register file, as long as the target says
+    regs: Vec<i32>,
     stack: Vec<i32>,
+    // everything written by Out, in order
+    output: Vec<i32>,
+    // set by Ret
+    returned: Option<i32>,
+    halted: bool,
+    // what the current instruction read and wrote, only kept while tracing
+    tracing: bool,
+    reads: Vec<(InsnOperand, i32)>,
+    written: Option<Written>,
+    limits: Limits,
+    // instructions run so far
+    steps: u64,
+}
+
+// What a program computed: the operand of the Ret it stopped at, or, for programs
+// that stop with Halt or by running past their last instruction, every value
+// written by Out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    List(Vec<i32>),
+}
+
+// Where a program is after State::advance
+#[derive(Debug)]
+pub enum Status {
+    // it stopped partway, advance again to go on
+    Running,
+    Finished(Value),
+    // it failed at State::index, advancing again fails the same way
+    Error(Error),
+}
+
+// Bounds on how much a program may do, for running programs you don't trust.
+// None is no bound
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Limits {
+    // instructions it may run
+    pub fuel: Option<u64>,
+    // stack slots it may have at once
+    pub stack: Option<usize>,
+}
+
+// The limit a program ran into, with its bound
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Limit {
+    Fuel(u64),
+    Stack(usize),
+}
+
+// How far a program got before a limit stopped it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partial {
+    pub steps: u64,
+    pub regs: Vec<i32>,
+    pub stack: Vec<i32>,
+    pub output: Vec<i32>,
 }
 
+// A program partway through running. run does it in one go, advance lets the
+// caller run it a few steps at a time, to interleave many programs on one
+// thread or report progress:
+//
+//   let mut state = State::new(&HELIUM, insns);
+//   while let Status::Running = state.advance(1000) {
+//       println!("{} steps so far", state.steps());
+//   }
 impl State {
+    pub fn new(target: &Target, instructions: Vec<Instruction>) -> State {
+        State {
+            i: 0,
+            code: instructions.iter().map(|insn| decode(insn, target)).collect(),
+            instructions,
+            regs: vec![0; target.registers],
+            stack: vec![],
+            output: vec![],
+            returned: None,
+            halted: false,
+            tracing: false,
+            reads: vec![],
+            written: None,
+            limits: Limits::default(),
+            steps: 0,
+        }
+    }
 
-    fn step(&mut self) -> Result<(), String> {
-        let insn = self.instructions.get(self.i).unwrap();
-        println!("running: {:?}", insn);
-        match insn.opcode {
-            InsnOpcode::Ldc => match insn.operands.get(0) {
-                None => return Err(format!("Illegal ldc instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal ldc instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(n) => n.clone(),
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            _ => return Err(format!("Illegal ldc instruction: {:?}", insn)),
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal ldc instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 += src,
-                            InsnOperand::Reg1 => self.reg1 += src,
-                            InsnOperand::Reg2 => self.reg2 += src,
-                            InsnOperand::Reg3 => self.reg3 += src,
-                            InsnOperand::Reg4 => self.reg4 += src,
-                            InsnOperand::Reg5 => self.reg5 += src,
-                            InsnOperand::Reg6 => self.reg6 += src,
-                            InsnOperand::Reg7 => self.reg7 += src,
-                        }
-                    }
-                },
-            },
-            InsnOpcode::Add => match insn.operands.get(0) {
-                None => return Err(format!("Illegal add instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal add instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal add instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            InsnOperand::Reg0 => self.reg0,
-                            InsnOperand::Reg1 => self.reg1,
-                            InsnOperand::Reg2 => self.reg2,
-                            InsnOperand::Reg3 => self.reg3,
-                            InsnOperand::Reg4 => self.reg4,
-                            InsnOperand::Reg5 => self.reg5,
-                            InsnOperand::Reg6 => self.reg6,
-                            InsnOperand::Reg7 => self.reg7,
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal add instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 += src,
-                            InsnOperand::Reg1 => self.reg1 += src,
-                            InsnOperand::Reg2 => self.reg2 += src,
-                            InsnOperand::Reg3 => self.reg3 += src,
-                            InsnOperand::Reg4 => self.reg4 += src,
-                            InsnOperand::Reg5 => self.reg5 += src,
-                            InsnOperand::Reg6 => self.reg6 += src,
-                            InsnOperand::Reg7 => self.reg7 += src,
-                        }
-                    }
-                },
-            },
-            InsnOpcode::Sub => match insn.operands.get(0) {
-                None => return Err(format!("Illegal sub instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal sub instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal sub instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            InsnOperand::Reg0 => self.reg0,
-                            InsnOperand::Reg1 => self.reg1,
-                            InsnOperand::Reg2 => self.reg2,
-                            InsnOperand::Reg3 => self.reg3,
-                            InsnOperand::Reg4 => self.reg4,
-                            InsnOperand::Reg5 => self.reg5,
-                            InsnOperand::Reg6 => self.reg6,
-                            InsnOperand::Reg7 => self.reg7,
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal sub instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 -= src,
-                            InsnOperand::Reg1 => self.reg1 -= src,
-                            InsnOperand::Reg2 => self.reg2 -= src,
-                            InsnOperand::Reg3 => self.reg3 -= src,
-                            InsnOperand::Reg4 => self.reg4 -= src,
-                            InsnOperand::Reg5 => self.reg5 -= src,
-                            InsnOperand::Reg6 => self.reg6 -= src,
-                            InsnOperand::Reg7 => self.reg7 -= src,
-                        }
-                    }
-                },
-            },
-            InsnOpcode::Mul => match insn.operands.get(0) {
-                None => return Err(format!("Illegal mul instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal mul instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal mul instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            InsnOperand::Reg0 => self.reg0,
-                            InsnOperand::Reg1 => self.reg1,
-                            InsnOperand::Reg2 => self.reg2,
-                            InsnOperand::Reg3 => self.reg3,
-                            InsnOperand::Reg4 => self.reg4,
-                            InsnOperand::Reg5 => self.reg5,
-                            InsnOperand::Reg6 => self.reg6,
-                            InsnOperand::Reg7 => self.reg7,
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal mul instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 *= src,
-                            InsnOperand::Reg1 => self.reg1 *= src,
-                            InsnOperand::Reg2 => self.reg2 *= src,
-                            InsnOperand::Reg3 => self.reg3 *= src,
-                            InsnOperand::Reg4 => self.reg4 *= src,
-                            InsnOperand::Reg5 => self.reg5 *= src,
-                            InsnOperand::Reg6 => self.reg6 *= src,
-                            InsnOperand::Reg7 => self.reg7 *= src,
-                        }
-                    }
-                },
-            },
-            InsnOpcode::Div => match insn.operands.get(0) {
-                None => return Err(format!("Illegal div instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal div instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal div instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            InsnOperand::Reg0 => self.reg0,
-                            InsnOperand::Reg1 => self.reg1,
-                            InsnOperand::Reg2 => self.reg2,
-                            InsnOperand::Reg3 => self.reg3,
-                            InsnOperand::Reg4 => self.reg4,
-                            InsnOperand::Reg5 => self.reg5,
-                            InsnOperand::Reg6 => self.reg6,
-                            InsnOperand::Reg7 => self.reg7,
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal div instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 /= src,
-                            InsnOperand::Reg1 => self.reg1 /= src,
-                            InsnOperand::Reg2 => self.reg2 /= src,
-                            InsnOperand::Reg3 => self.reg3 /= src,
-                            InsnOperand::Reg4 => self.reg4 /= src,
-                            InsnOperand::Reg5 => self.reg5 /= src,
-                            InsnOperand::Reg6 => self.reg6 /= src,
-                            InsnOperand::Reg7 => self.reg7 /= src,
-                        }
-                    }
-                },
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Whether the program stopped, by Ret, Halt or running past its end
+    pub fn finished(&self) -> bool {
+        self.halted || self.i >= self.instructions.len()
+    }
+
+    // The instruction it runs next
+    pub fn index(&self) -> usize {
+        self.i
+    }
+
+    // Instructions run so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Runs up to n more instructions
+    pub fn advance(&mut self, n: usize) -> Status {
+        self.advance_traced(n, &mut NoTrace)
+    }
+
+    // The same, handing every instruction executed to sink
+    pub fn advance_traced(&mut self, n: usize, sink: &mut dyn Sink) -> Status {
+        // nothing to record or count against, so skip the bookkeeping
+        let fast = !sink.enabled() && self.limits == Limits::default();
+        self.tracing = false;
+
+        for _ in 0..n {
+            if self.finished() {
+                break;
+            }
+            let stepped = if fast { self.step() } else { self.step_traced(sink) };
+            if let Err(err) = stepped {
+                return Status::Error(err);
+            }
+        }
+
+        if self.finished() {
+            return Status::Finished(self.result());
+        }
+        Status::Running
+    }
+
+    // The value of an operand, wherever it lives
+    fn read(&self, operand: InsnOperand) -> Result<i32, String> {
+        match operand {
+            InsnOperand::Imm(n) => Ok(n),
+            InsnOperand::Stack(n) => match self.stack.get(n) {
+                None => Err(format!("Stack index out of bounds: {}", n)),
+                Some(v) => Ok(*v),
             },
-            InsnOpcode::Push => match insn.operands.get(0) {
-                None => return Err(format!("Illegal push instruction: {:?}", insn)),
-                Some(arg1) => match arg1 {
-                    InsnOperand::Imm(n) => self.stack.push(n.clone()),
-                    InsnOperand::Stack(_) => {
-                        return Err(format!("Illegal push instruction: {:?}", insn))
-                    }
-                    InsnOperand::Reg0 => self.stack.push(self.reg0),
-                    InsnOperand::Reg1 => self.stack.push(self.reg1),
-                    InsnOperand::Reg2 => self.stack.push(self.reg2),
-                    InsnOperand::Reg3 => self.stack.push(self.reg3),
-                    InsnOperand::Reg4 => self.stack.push(self.reg4),
-                    InsnOperand::Reg5 => self.stack.push(self.reg5),
-                    InsnOperand::Reg6 => self.stack.push(self.reg6),
-                    InsnOperand::Reg7 => self.stack.push(self.reg7),
-                },
+            InsnOperand::Reg(n) => match self.regs.get(n as usize) {
+                None => Err(format!("No such register: {}", operand)),
+                Some(v) => Ok(*v),
             },
-            InsnOpcode::Pop => match insn.operands.get(0) {
-                None => return Err(format!("Illegal pop instruction: {:?}", insn)),
-                Some(arg1) => match self.stack.pop() {
-                    None => return Err(format!("Illegal pop instruction: {:?}", insn)),
-                    Some(val) => match arg1 {
-                        InsnOperand::Imm(_) | InsnOperand::Stack(_) => {
-                            return Err(format!("Illegal pop instruction: {:?}", insn))
-                        }
-                        InsnOperand::Reg0 => self.reg0 = val,
-                        InsnOperand::Reg1 => self.reg1 = val,
-                        InsnOperand::Reg2 => self.reg2 = val,
-                        InsnOperand::Reg3 => self.reg3 = val,
-                        InsnOperand::Reg4 => self.reg4 = val,
-                        InsnOperand::Reg5 => self.reg5 = val,
-                        InsnOperand::Reg6 => self.reg6 = val,
-                        InsnOperand::Reg7 => self.reg7 = val,
-                    },
-                },
+        }
+    }
+
+    // read, for the running program, noting down what was read for the trace
+    fn load(&mut self, src: Src) -> Result<i32, String> {
+        let value = match src {
+            Src::Imm(n) => return Ok(n),
+            Src::Stack(n) => match self.stack.get(n as usize) {
+                None => return Err(format!("Stack index out of bounds: {}", n)),
+                Some(v) => *v,
             },
-            InsnOpcode::Copy => match insn.operands.get(0) {
-                None => return Err(format!("Illegal copy instruction: {:?}", insn)),
-                Some(arg1) => match insn.operands.get(1) {
-                    None => return Err(format!("Illegal copy instruction: {:?}", insn)),
-                    Some(arg2) => {
-                        let src: i32 = match arg2 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal copy instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => v.clone(),
-                            },
-                            InsnOperand::Reg0 => self.reg0,
-                            InsnOperand::Reg1 => self.reg1,
-                            InsnOperand::Reg2 => self.reg2,
-                            InsnOperand::Reg3 => self.reg3,
-                            InsnOperand::Reg4 => self.reg4,
-                            InsnOperand::Reg5 => self.reg5,
-                            InsnOperand::Reg6 => self.reg6,
-                            InsnOperand::Reg7 => self.reg7,
-                        };
-
-                        match arg1 {
-                            InsnOperand::Imm(_) => {
-                                return Err(format!("Illegal copy instruction: {:?}", insn))
-                            }
-                            InsnOperand::Stack(n) => match self.stack.get_mut(n.clone()) {
-                                None => {
-                                    return Err(format!(
-                                        "Stack index out of bounds: {} {:?}",
-                                        n, insn
-                                    ));
-                                }
-                                Some(v) => *v += src,
-                            },
-                            InsnOperand::Reg0 => self.reg0 = src,
-                            InsnOperand::Reg1 => self.reg1 = src,
-                            InsnOperand::Reg2 => self.reg2 = src,
-                            InsnOperand::Reg3 => self.reg3 = src,
-                            InsnOperand::Reg4 => self.reg4 = src,
-                            InsnOperand::Reg5 => self.reg5 = src,
-                            InsnOperand::Reg6 => self.reg6 = src,
-                            InsnOperand::Reg7 => self.reg7 = src,
-                        }
-                    }
-                },
+            // decode checked the register is there
+            Src::Reg(n) => self.regs[n as usize],
+        };
+        if self.tracing {
+            self.reads.push((src.into(), value));
+        }
+
+        Ok(value)
+    }
+
+    fn store(&mut self, dst: Dst, value: i32) -> Result<(), String> {
+        if self.tracing {
+            self.written = Some(Written {
+                operand: dst.into(),
+                old: self.read(dst.into()).ok(),
+                new: value,
+            });
+        }
+
+        let slot = match dst {
+            Dst::Stack(n) => match self.stack.get_mut(n as usize) {
+                None => return Err(format!("Stack index out of bounds: {}", n)),
+                Some(v) => v,
             },
+            Dst::Reg(n) => &mut self.regs[n as usize],
+        };
+
+        *slot = value;
+        Ok(())
+    }
+
+    // Runs the instruction at i, untraced and unlimited. When it fails, i stays on it
+    fn step(&mut self) -> Result<(), Error> {
+        if let Err(message) = self.execute() {
+            return Err(self.runtime_error(message));
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    // The same with limits, handing sink an event for the instruction when it succeeds.
+    // When it fails, i stays on it
+    fn step_traced(&mut self, sink: &mut dyn Sink) -> Result<(), Error> {
+        let index = self.i;
+        self.check_limits()?;
+        self.tracing = sink.enabled();
+        self.reads.clear();
+        self.written = None;
+
+        if let Err(message) = self.execute() {
+            return Err(self.runtime_error(message));
+        }
+
+        if self.tracing {
+            sink.event(&Event {
+                index,
+                instruction: &self.instructions[index],
+                reads: &self.reads,
+                written: self.written,
+            })?;
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    // message as an error about the instruction at i
+    fn runtime_error(&self, message: String) -> Error {
+        Error::Runtime {
+            message,
+            index: self.i,
+            span: self.instructions[self.i].span,
+        }
+    }
+
+    // Stops the program before the instruction at i would go over a limit
+    fn check_limits(&self) -> Result<(), Error> {
+        let limit = match self.limits {
+            Limits { fuel: Some(fuel), .. } if self.steps >= fuel => Limit::Fuel(fuel),
+            Limits { stack: Some(stack), .. }
+                if self.instructions[self.i].opcode == InsnOpcode::Push && self.stack.len() >= stack =>
+            {
+                Limit::Stack(stack)
+            }
+            _ => return Ok(()),
+        };
+
+        Err(Error::Limit {
+            limit,
+            index: self.i,
+            span: self.instructions[self.i].span,
+            partial: Box::new(self.partial()),
+        })
+    }
+
+    fn partial(&self) -> Partial {
+        Partial {
+            steps: self.steps,
+            regs: self.regs.clone(),
+            stack: self.stack.clone(),
+            output: self.output.clone(),
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        let op = match &self.code[self.i] {
+            Ok(op) => *op,
+            Err(message) => return Err(message.clone()),
+        };
+
+        match op {
+            Op::Ldc(dst, src) | Op::Copy(dst, src) => {
+                let value = self.load(src)?;
+                self.store(dst, value)?;
+            }
+            Op::Add(dst, src) => self.arithmetic(Operand::Add, dst, src)?,
+            Op::Sub(dst, src) => self.arithmetic(Operand::Sub, dst, src)?,
+            Op::Mul(dst, src) => self.arithmetic(Operand::Mul, dst, src)?,
+            Op::Div(dst, src) => self.arithmetic(Operand::Div, dst, src)?,
+            Op::Push(src) => {
+                let value = self.load(src)?;
+                if self.tracing {
+                    self.written = Some(Written {
+                        operand: InsnOperand::Stack(self.stack.len()),
+                        old: None,
+                        new: value,
+                    });
+                }
+                self.stack.push(value);
+            }
+            Op::Pop(dst) => {
+                if self.stack.is_empty() {
+                    return Err(String::from("Pop from an empty stack"));
+                }
+                let value = self.load(Src::Stack(self.stack.len() as u32 - 1))?;
+                self.stack.pop();
+                self.store(dst, value)?;
+            }
+            Op::Out(src) => {
+                let value = self.load(src)?;
+                self.output.push(value);
+            }
+            Op::Ret(src) => {
+                self.returned = Some(self.load(src)?);
+                self.halted = true;
+            }
+            Op::Halt => self.halted = true,
         }
         self.i += 1;
 
         Ok(())
     }
+
+    fn arithmetic(&mut self, operand: Operand, dst: Dst, src: Src) -> Result<(), String> {
+        let lhs = self.load(dst.into())?;
+        let rhs = self.load(src)?;
+        self.store(dst, operand.apply(lhs, rhs)?)
+    }
+
+    fn result(&self) -> Value {
+        match self.returned {
+            Some(v) => Value::Int(v),
+            None => Value::List(self.output.clone()),
+        }
+    }
     pub fn debug(&self) -> StateDebug<'_> {
         StateDebug(self)
     }
@@ -383,41 +351,84 @@ impl fmt::Debug for StateDebug<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("State")
             .field("i", &self.0.i)
-            .field("reg0", &self.0.reg0)
-            .field("reg1", &self.0.reg1)
-            .field("reg2", &self.0.reg2)
-            .field("reg3", &self.0.reg3)
-            .field("reg4", &self.0.reg4)
-            .field("reg5", &self.0.reg5)
-            .field("reg6", &self.0.reg6)
-            .field("reg7", &self.0.reg7)
+            .field("regs", &self.0.regs)
             .field("stack", &self.0.stack)
+            .field("output", &self.0.output)
             .finish()
     }
 }
 
-pub fn run(instructions: Vec<Instruction>) -> Result<State, String> {
-    let mut state = State {
-        i: 0,
-        instructions,
-        reg0: 0,
-        reg1: 0,
-        reg2: 0,
-        reg3: 0,
-        reg4: 0,
-        reg5: 0,
-        reg6: 0,
-        reg7: 0,
-        stack: vec![],
-    };
-
-    while state.i < state.instructions.len() {
-        match state.step() {
-            Ok(()) => {}
-            Err(msg) => return Err(msg),
+pub fn run(instructions: Vec<Instruction>) -> Result<Value, Error> {
+    run_on(&HELIUM, instructions)
+}
+
+// Runs on a machine with target's register file
+pub fn run_on(target: &Target, instructions: Vec<Instruction>) -> Result<Value, Error> {
+    run_traced(target, instructions, &mut NoTrace)
+}
+
+// Runs, handing every instruction executed to sink
+pub fn run_traced(target: &Target, instructions: Vec<Instruction>, sink: &mut dyn Sink) -> Result<Value, Error> {
+    run_limited(target, instructions, &Limits::default(), sink)
+}
+
+// Runs within limits, failing with Error::Limit and how far it got when it hits one
+pub fn run_limited(
+    target: &Target,
+    instructions: Vec<Instruction>,
+    limits: &Limits,
+    sink: &mut dyn Sink,
+) -> Result<Value, Error> {
+    let mut state = State::new(target, instructions);
+    state.set_limits(*limits);
+
+    loop {
+        match state.advance_traced(usize::MAX, sink) {
+            Status::Running => continue,
+            Status::Finished(value) => return Ok(value),
+            Status::Error(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+
+    #[test]
+    fn limits() {
+        let insns = assemble("Push 1\nPush 2\nPush 3\nOut Stack(2)\nHalt").unwrap().instructions;
+        let fuel = Limits { fuel: Some(4), stack: None };
+        match run_limited(&HELIUM, insns.clone(), &fuel, &mut NoTrace) {
+            Err(Error::Limit { limit: Limit::Fuel(4), index: 4, partial, .. }) => {
+                assert_eq!(partial.steps, 4);
+                assert_eq!(partial.stack, vec![1, 2, 3]);
+                assert_eq!(partial.output, vec![3]);
+            }
+            other => panic!("{:?}", other),
         }
-        println!("step: {:#?}", state.debug());
+
+        let stack = Limits { fuel: None, stack: Some(2) };
+        let err = run_limited(&HELIUM, insns.clone(), &stack, &mut NoTrace).unwrap_err();
+        assert_eq!(err.to_string(), "Limit reached in instruction 2: Stack limit of 2 slots exceeded");
+
+        let roomy = Limits { fuel: Some(5), stack: Some(3) };
+        assert_eq!(run_limited(&HELIUM, insns, &roomy, &mut NoTrace).unwrap(), Value::List(vec![3]));
     }
 
-    Ok(state)
+    #[test]
+    fn time_slicing() {
+        let counting = assemble("Out 1\nOut 2\nOut 3\nHalt").unwrap().instructions;
+        let failing = assemble("Ldc Reg0, 1\nDiv Reg0, 0\nRet Reg0").unwrap().instructions;
+        let mut states = [State::new(&HELIUM, counting), State::new(&HELIUM, failing)];
+
+        assert!(matches!(states[0].advance(2), Status::Running));
+        assert!(matches!(states[1].advance(2), Status::Error(Error::Runtime { index: 1, .. })));
+        assert_eq!((states[0].index(), states[0].steps()), (2, 2));
+        assert!(matches!(states[0].advance(5), Status::Finished(Value::List(list)) if list == vec![1, 2, 3]));
+        // nothing left to run, it just says it's done again
+        assert!(matches!(states[0].advance(1), Status::Finished(_)));
+        assert!(matches!(states[1].advance(1), Status::Error(Error::Runtime { index: 1, .. })));
+    }
 }
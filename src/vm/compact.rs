@@ -0,0 +1,131 @@
+// The form the vm runs instructions in: each one checked and decoded once,
+// when the state is set up, into a small Copy value with its operands typed
+// by what the opcode does with them. Running it then needs no operand
+// lookups, count checks or kind checks, just a match on the op.
+//
+// Registers in an Op are known to be in the target's register file. Stack
+// slots still depend on the stack at the time, so they're checked as they're used.
+use crate::helium::verify::check;
+use crate::helium::{InsnOpcode, InsnOperand, Instruction, Target};
+
+// What an instruction writes to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dst {
+    Stack(u32),
+    Reg(u8),
+}
+
+// What it reads from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Src {
+    Imm(i32),
+    Stack(u32),
+    Reg(u8),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    Ldc(Dst, Src),
+    Push(Src),
+    Pop(Dst),
+    Copy(Dst, Src),
+    Add(Dst, Src),
+    Sub(Dst, Src),
+    Mul(Dst, Src),
+    Div(Dst, Src),
+    Out(Src),
+    Ret(Src),
+    Halt,
+}
+
+impl From<Src> for InsnOperand {
+    fn from(src: Src) -> InsnOperand {
+        match src {
+            Src::Imm(n) => InsnOperand::Imm(n),
+            Src::Stack(n) => InsnOperand::Stack(n as usize),
+            Src::Reg(n) => InsnOperand::Reg(n),
+        }
+    }
+}
+
+impl From<Dst> for InsnOperand {
+    fn from(dst: Dst) -> InsnOperand {
+        match dst {
+            Dst::Stack(n) => InsnOperand::Stack(n as usize),
+            Dst::Reg(n) => InsnOperand::Reg(n),
+        }
+    }
+}
+
+// Arithmetic reads its destination too
+impl From<Dst> for Src {
+    fn from(dst: Dst) -> Src {
+        match dst {
+            Dst::Stack(n) => Src::Stack(n),
+            Dst::Reg(n) => Src::Reg(n),
+        }
+    }
+}
+
+// Slots past u32 can't be in a .hbc file either
+fn slot(n: usize) -> Result<u32, String> {
+    u32::try_from(n).map_err(|_| format!("Stack slot too large: {}", n))
+}
+
+fn src(operand: InsnOperand) -> Result<Src, String> {
+    match operand {
+        InsnOperand::Imm(n) => Ok(Src::Imm(n)),
+        InsnOperand::Stack(n) => Ok(Src::Stack(slot(n)?)),
+        InsnOperand::Reg(n) => Ok(Src::Reg(n)),
+    }
+}
+
+fn dst(operand: InsnOperand) -> Result<Dst, String> {
+    match operand {
+        InsnOperand::Imm(_) => Err(String::from("Can't write to an immediate")),
+        InsnOperand::Stack(n) => Ok(Dst::Stack(slot(n)?)),
+        InsnOperand::Reg(n) => Ok(Dst::Reg(n)),
+    }
+}
+
+// The op insn runs as on target, or what's wrong with it
+pub fn decode(insn: &Instruction, target: &Target) -> Result<Op, String> {
+    // after this the operands are known to be there and of the right kinds
+    check(insn, target)?;
+    let a = insn.operands.first().copied();
+    let b = insn.operands.get(1).copied();
+
+    Ok(match (insn.opcode, a, b) {
+        (InsnOpcode::Ldc, Some(a), Some(b)) => Op::Ldc(dst(a)?, src(b)?),
+        (InsnOpcode::Copy, Some(a), Some(b)) => Op::Copy(dst(a)?, src(b)?),
+        (InsnOpcode::Add, Some(a), Some(b)) => Op::Add(dst(a)?, src(b)?),
+        (InsnOpcode::Sub, Some(a), Some(b)) => Op::Sub(dst(a)?, src(b)?),
+        (InsnOpcode::Mul, Some(a), Some(b)) => Op::Mul(dst(a)?, src(b)?),
+        (InsnOpcode::Div, Some(a), Some(b)) => Op::Div(dst(a)?, src(b)?),
+        (InsnOpcode::Push, Some(a), _) => Op::Push(src(a)?),
+        (InsnOpcode::Pop, Some(a), _) => Op::Pop(dst(a)?),
+        (InsnOpcode::Out, Some(a), _) => Op::Out(src(a)?),
+        (InsnOpcode::Ret, Some(a), _) => Op::Ret(src(a)?),
+        (InsnOpcode::Halt, _, _) => Op::Halt,
+        _ => return Err(format!("Illegal instruction: {}", insn)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+    use crate::helium::HELIUM;
+
+    #[test]
+    fn decoding() {
+        let insns = assemble("Add Stack(1), Reg2\nPush 7\nAdd Reg9, 1\nPop 3").unwrap().instructions;
+        let ops: Vec<Result<Op, String>> = insns.iter().map(|insn| decode(insn, &HELIUM)).collect();
+
+        assert_eq!(ops[0], Ok(Op::Add(Dst::Stack(1), Src::Reg(2))));
+        assert_eq!(ops[1], Ok(Op::Push(Src::Imm(7))));
+        assert!(ops[2].is_err() && ops[3].is_err());
+        // small enough to pass around by value
+        assert!(std::mem::size_of::<Op>() <= 20);
+    }
+}
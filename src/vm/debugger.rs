@@ -0,0 +1,440 @@
+// Interactive step debugger: runs a program one command at a time, stopping
+// at breakpoints on instruction indices or source lines
+//
+//   step [n]          s   run n instructions, 1 by default
+//   continue          c   run until a breakpoint or the end of the program
+//   back [n]              undo n instructions, 1 by default
+//   goto n                go back or forward to where the program was after n steps
+//   lastwrite op          the instruction that last wrote op, and what it wrote
+//   break n           b   stop before instruction n
+//   break line n          stop before the first instruction of source line n
+//   delete n | line n     remove a breakpoint
+//   breakpoints           list them
+//   regs                  the register file
+//   stack                 the stack, bottom first
+//   output                everything written by Out so far
+//   watch op              show op (Reg3, Stack(0), ...) at every stop
+//   unwatch op
+//   list [n]          l   disassembly around the current instruction, n either side
+//   save file             write a snapshot of the program where it is
+//   restore file          go on from a snapshot instead
+//   help
+//   quit              q
+use crate::error::Error;
+use crate::helium::asm::parse_operand;
+use crate::helium::{InsnOperand, Instruction, Target};
+use crate::vm::history::History;
+use crate::vm::State;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Breakpoint {
+    Index(usize),
+    // 1 based, like the lines errors point at
+    Line(usize),
+}
+
+pub struct Debugger {
+    state: State,
+    // the source the program was compiled from, for line breakpoints
+    source: Option<String>,
+    breakpoints: Vec<Breakpoint>,
+    watches: Vec<InsnOperand>,
+    // the error the last instruction failed with, the program can't go on after it
+    failed: Option<Error>,
+    // everything run since the start or the last restore, to go back through
+    history: History,
+}
+
+impl Debugger {
+    pub fn new(target: &Target, instructions: Vec<Instruction>, source: Option<String>) -> Debugger {
+        Debugger {
+            state: State::new(target, instructions),
+            source,
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            failed: None,
+            history: History::new(),
+        }
+    }
+
+    // The source line instruction index came from, if it's known
+    fn line(&self, index: usize) -> Option<usize> {
+        let source = self.source.as_ref()?;
+        let span = self.state.instructions.get(index)?.span?;
+        Some(source.get(..span.start)?.matches('\n').count() + 1)
+    }
+
+    fn breaks_at(&self, index: usize) -> bool {
+        self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Index(n) => *n == index,
+            // only when the line is entered, not at every instruction on it
+            Breakpoint::Line(n) => {
+                self.line(index) == Some(*n) && (index == 0 || self.line(index - 1) != Some(*n))
+            }
+        })
+    }
+
+    // Runs up to n instructions, or until a breakpoint when n is None
+    fn run(&mut self, n: Option<usize>) -> String {
+        if let Some(err) = &self.failed {
+            return format!("the program failed: {}\n", err);
+        }
+
+        let mut steps = 0;
+        while !self.state.finished() {
+            if n.is_some_and(|n| steps >= n) {
+                break;
+            }
+            // the instruction it's stopped at doesn't stop it again
+            if n.is_none() && steps > 0 && self.breaks_at(self.state.i) {
+                return format!("breakpoint\n{}", self.stop());
+            }
+
+            if let Err(err) = self.state.step_traced(&mut self.history) {
+                let message = format!("{}\n", err);
+                self.failed = Some(err);
+                return message + &self.stop();
+            }
+            steps += 1;
+        }
+
+        self.stop()
+    }
+
+    // Undoes up to n instructions, which also gets it past a failure
+    fn back(&mut self, n: u64) -> String {
+        for _ in 0..n {
+            if !self.history.step_back(&mut self.state) {
+                break;
+            }
+            self.failed = None;
+        }
+
+        self.stop()
+    }
+
+    // Goes back or forward to after step n
+    fn goto(&mut self, n: u64) -> Result<String, String> {
+        let steps = self.state.steps();
+        let earliest = steps - self.history.len() as u64;
+        if n < earliest {
+            return Err(format!("the history only goes back to step {}", earliest));
+        }
+
+        if n < steps {
+            return Ok(self.back(steps - n));
+        }
+        Ok(self.run(Some((n - steps) as usize)))
+    }
+
+    fn last_write(&self, operand: InsnOperand) -> String {
+        let earliest = self.state.steps() as usize - self.history.len();
+        match self.history.last_write(operand) {
+            None => format!("nothing has written {} since step {}\n", operand, earliest),
+            Some((step, delta)) => {
+                let written = delta.written.unwrap();
+                let old = written.old.map_or(String::new(), |old| format!("{} ", old));
+                format!(
+                    "step {}, {}: {} | {}: {}-> {}\n",
+                    earliest + step + 1,
+                    delta.index,
+                    self.state.instructions[delta.index],
+                    operand,
+                    old,
+                    written.new
+                )
+            }
+        }
+    }
+
+    // Where the program is and what the watched operands hold
+    fn stop(&self) -> String {
+        let mut buff = String::new();
+        if self.failed.is_none() && self.state.finished() {
+            buff.push_str(&format!("finished: {:?}\n", self.state.result()));
+        } else {
+            buff.push_str(&format!("{}: {}\n", self.state.i, self.state.instructions[self.state.i]));
+        }
+
+        for watch in &self.watches {
+            match self.state.read(*watch) {
+                Ok(value) => buff.push_str(&format!("  {} = {}\n", watch, value)),
+                Err(err) => buff.push_str(&format!("  {}: {}\n", watch, err)),
+            }
+        }
+
+        buff
+    }
+
+    fn list(&self, around: usize) -> String {
+        let insns = &self.state.instructions;
+        let from = self.state.i.saturating_sub(around);
+        let to = (self.state.i + around + 1).min(insns.len());
+
+        let mut buff = String::new();
+        for (index, insn) in insns.iter().enumerate().take(to).skip(from) {
+            let marker = if index == self.state.i { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&Breakpoint::Index(index)) { "*" } else { " " };
+            buff.push_str(&format!("{}{} {}: {}\n", marker, breakpoint, index, insn));
+        }
+        if self.state.i >= insns.len() {
+            buff.push_str("=>   end\n");
+        }
+
+        buff
+    }
+
+    fn breakpoint(&self, args: &[&str]) -> Result<Breakpoint, String> {
+        match args {
+            [n] => match n.parse::<usize>() {
+                Ok(n) if n < self.state.instructions.len() => Ok(Breakpoint::Index(n)),
+                Ok(n) => Err(format!("there's no instruction {}", n)),
+                Err(_) => Err(format!("not an instruction index: {}", n)),
+            },
+            ["line", n] => match n.parse::<usize>() {
+                Err(_) | Ok(0) => Err(format!("not a line number: {}", n)),
+                Ok(_) if self.source.is_none() => Err(String::from("there's no source to find lines in")),
+                Ok(n) => Ok(Breakpoint::Line(n)),
+            },
+            _ => Err(String::from("expected an instruction index or `line n`")),
+        }
+    }
+
+    fn operand(args: &[&str]) -> Result<InsnOperand, String> {
+        match args {
+            [text] => match parse_operand(text) {
+                Some(InsnOperand::Imm(_)) | None => Err(format!("not a register or stack slot: {}", text)),
+                Some(operand) => Ok(operand),
+            },
+            _ => Err(String::from("expected a register or stack slot")),
+        }
+    }
+
+    // Runs one debugger command and returns what it prints
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            None => return Ok(String::new()),
+            Some((name, args)) => (*name, args),
+        };
+
+        match name {
+            "step" | "s" => match args {
+                [] => Ok(self.run(Some(1))),
+                [n] => match n.parse::<usize>() {
+                    Ok(n) => Ok(self.run(Some(n))),
+                    Err(_) => Err(format!("not a number of steps: {}", n)),
+                },
+                _ => Err(String::from("step takes at most one count")),
+            },
+            "continue" | "c" => Ok(self.run(None)),
+            "back" => match args {
+                [] => Ok(self.back(1)),
+                [n] => match n.parse::<u64>() {
+                    Ok(n) => Ok(self.back(n)),
+                    Err(_) => Err(format!("not a number of steps: {}", n)),
+                },
+                _ => Err(String::from("back takes at most one count")),
+            },
+            "goto" => match args {
+                [n] => match n.parse::<u64>() {
+                    Ok(n) => self.goto(n),
+                    Err(_) => Err(format!("not a step number: {}", n)),
+                },
+                _ => Err(String::from("goto needs a step number")),
+            },
+            "lastwrite" => Ok(self.last_write(Debugger::operand(args)?)),
+            "break" | "b" => {
+                let breakpoint = self.breakpoint(args)?;
+                if !self.breakpoints.contains(&breakpoint) {
+                    self.breakpoints.push(breakpoint);
+                }
+                Ok(String::new())
+            }
+            "delete" => {
+                let breakpoint = self.breakpoint(args)?;
+                match self.breakpoints.iter().position(|b| *b == breakpoint) {
+                    None => Err(String::from("there's no such breakpoint")),
+                    Some(i) => {
+                        self.breakpoints.remove(i);
+                        Ok(String::new())
+                    }
+                }
+            }
+            "breakpoints" => Ok(self
+                .breakpoints
+                .iter()
+                .map(|breakpoint| match breakpoint {
+                    Breakpoint::Index(n) => format!("instruction {}\n", n),
+                    Breakpoint::Line(n) => format!("line {}\n", n),
+                })
+                .collect()),
+            "regs" => Ok(self
+                .state
+                .regs
+                .iter()
+                .enumerate()
+                .map(|(n, value)| format!("Reg{} = {}\n", n, value))
+                .collect()),
+            "stack" => Ok(self
+                .state
+                .stack
+                .iter()
+                .enumerate()
+                .map(|(n, value)| format!("Stack({}) = {}\n", n, value))
+                .collect()),
+            "output" => Ok(format!("{:?}\n", self.state.output)),
+            "watch" => {
+                let operand = Debugger::operand(args)?;
+                if !self.watches.contains(&operand) {
+                    self.watches.push(operand);
+                }
+                Ok(self.stop())
+            }
+            "unwatch" => {
+                let operand = Debugger::operand(args)?;
+                self.watches.retain(|watch| *watch != operand);
+                Ok(String::new())
+            }
+            "list" | "l" => match args {
+                [] => Ok(self.list(3)),
+                [n] => match n.parse::<usize>() {
+                    Ok(n) => Ok(self.list(n)),
+                    Err(_) => Err(format!("not a number of instructions: {}", n)),
+                },
+                _ => Err(String::from("list takes at most one count")),
+            },
+            "save" => match args {
+                [path] => match self.state.save(path, self.source.as_deref()) {
+                    Ok(()) => Ok(String::new()),
+                    Err(err) => Err(err.to_string()),
+                },
+                _ => Err(String::from("save needs a file")),
+            },
+            "restore" => match args {
+                [path] => match State::open(path) {
+                    Ok((state, source)) => {
+                        // the old source doesn't go with the restored program
+                        self.state = state;
+                        self.source = source;
+                        self.failed = None;
+                        self.history = History::new();
+                        Ok(self.stop())
+                    }
+                    Err(err) => Err(err.to_string()),
+                },
+                _ => Err(String::from("restore needs a file")),
+            },
+            "help" => Ok(String::from(
+                "step [n], continue, back [n], goto n, lastwrite op, break n, break line n,\n\
+                 delete n, delete line n, breakpoints,\n\
+                 regs, stack, output, watch op, unwatch op, list [n], save file, restore file, quit\n",
+            )),
+            _ => Err(format!("unknown command `{}`, try help", name)),
+        }
+    }
+
+    // Reads commands from input until quit or the end of it
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> Result<(), Error> {
+        write!(output, "{}(debug) ", self.list(0))?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "quit" | "q") {
+                break;
+            }
+
+            match self.command(&line) {
+                Ok(text) => write!(output, "{}", text)?,
+                Err(message) => writeln!(output, "error: {}", message)?,
+            }
+            write!(output, "(debug) ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+    use crate::helium::HELIUM;
+    use crate::{compile, parse, tokenize};
+
+    #[test]
+    fn breakpoints_and_watches() {
+        let insns = assemble("Ldc Reg0, 1\nAdd Reg0, 2\nAdd Reg0, 3\nRet Reg0").unwrap().instructions;
+        let mut debugger = Debugger::new(&HELIUM, insns, None);
+
+        debugger.command("break 2").unwrap();
+        assert_eq!(debugger.command("watch Reg0").unwrap(), "0: Ldc Reg0, Imm(1)\n  Reg0 = 0\n");
+        assert_eq!(debugger.command("c").unwrap(), "breakpoint\n2: Add Reg0, Imm(3)\n  Reg0 = 3\n");
+        assert_eq!(debugger.command("list 1").unwrap(), "    1: Add Reg0, Imm(2)\n=>* 2: Add Reg0, Imm(3)\n    3: Ret Reg0\n");
+        assert_eq!(debugger.command("s").unwrap(), "3: Ret Reg0\n  Reg0 = 6\n");
+        assert_eq!(debugger.command("c").unwrap(), "finished: Int(6)\n  Reg0 = 6\n");
+        assert!(debugger.command("break 9").is_err());
+    }
+
+    #[test]
+    fn line_breakpoints() {
+        let source = "1+2\n7-3/0\n4";
+        let insns = compile(parse(tokenize(source.as_bytes()).unwrap()).unwrap()).unwrap();
+        let mut debugger = Debugger::new(&HELIUM, insns, Some(String::from(source)));
+
+        debugger.command("break line 2").unwrap();
+        let stop = debugger.command("continue").unwrap();
+        assert!(stop.starts_with("breakpoint\n"), "{}", stop);
+        assert_eq!(debugger.line(debugger.state.i), Some(2));
+        // the division by zero stops it for good
+        assert!(debugger.command("c").unwrap().starts_with("Runtime error"));
+        assert!(debugger.command("s").unwrap().starts_with("the program failed"));
+    }
+
+    #[test]
+    fn time_travel() {
+        let insns = assemble("Ldc Reg3, 1\nAdd Reg3, 2\nOut Reg3\nDiv Reg3, 0\nRet Reg3").unwrap().instructions;
+        let mut debugger = Debugger::new(&HELIUM, insns, None);
+
+        assert!(debugger.command("c").unwrap().starts_with("Runtime error in instruction 3"));
+        assert_eq!(debugger.command("lastwrite Reg3").unwrap(), "step 2, 1: Add Reg3, Imm(2) | Reg3: 1 -> 3\n");
+        assert_eq!(debugger.command("back").unwrap(), "2: Out Reg3\n");
+        assert_eq!(debugger.command("output").unwrap(), "[]\n");
+        assert_eq!(debugger.command("goto 1").unwrap(), "1: Add Reg3, Imm(2)\n");
+        assert_eq!(debugger.command("lastwrite Reg3").unwrap(), "step 1, 0: Ldc Reg3, Imm(1) | Reg3: 0 -> 1\n");
+        assert_eq!(debugger.command("goto 3").unwrap(), "3: Div Reg3, Imm(0)\n");
+        assert_eq!(debugger.command("output").unwrap(), "[3]\n");
+        assert_eq!(debugger.command("goto 0").unwrap(), "0: Ldc Reg3, Imm(1)\n");
+        assert_eq!(debugger.command("lastwrite Reg3").unwrap(), "nothing has written Reg3 since step 0\n");
+    }
+
+    #[test]
+    fn restore_takes_the_snapshots_source() {
+        let path = std::env::temp_dir().join(format!("calc-debugger-{}.hvs", std::process::id()));
+        let path = path.to_str().unwrap();
+        let source = "1+2\n7-3/0\n4";
+        let insns = compile(parse(tokenize(source.as_bytes()).unwrap()).unwrap()).unwrap();
+        let mut saved = Debugger::new(&HELIUM, insns, Some(String::from(source)));
+        saved.command("step").unwrap();
+        saved.command(&format!("save {}", path)).unwrap();
+
+        // a different program, with a source of its own
+        let insns = assemble("Ldc Reg0, 1\nRet Reg0").unwrap().instructions;
+        let mut debugger = Debugger::new(&HELIUM, insns, Some(String::from("1")));
+        debugger.command(&format!("restore {}", path)).unwrap();
+        assert_eq!(debugger.source.as_deref(), Some(source));
+        assert_eq!(debugger.line(debugger.state.i), Some(2));
+
+        // and none if the snapshot doesn't have one
+        let insns = assemble("Ldc Reg0, 1\nRet Reg0").unwrap().instructions;
+        Debugger::new(&HELIUM, insns, None).command(&format!("save {}", path)).unwrap();
+        debugger.command(&format!("restore {}", path)).unwrap();
+        assert!(debugger.source.is_none());
+        assert!(debugger.command("break line 1").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
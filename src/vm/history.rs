@@ -0,0 +1,146 @@
+// Time travel: a trace sink that keeps what every instruction changed, so the
+// state can be wound back one instruction at a time and the last write to a
+// register or stack slot can be looked up
+//
+// Going forward again is just running again, the vm is deterministic, so only
+// the undo side is kept.
+use crate::helium::{InsnOpcode, InsnOperand};
+use crate::vm::trace::{Event, Sink, Written};
+use crate::vm::State;
+use std::io;
+
+// What one instruction changed, enough to undo it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    // the instruction that ran
+    pub index: usize,
+    pub opcode: InsnOpcode,
+    pub written: Option<Written>,
+    // the value Pop took off the stack
+    pub popped: Option<i32>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    // one per step, in the order they ran
+    deltas: Vec<Delta>,
+}
+
+impl Sink for History {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let opcode = event.instruction.opcode;
+        self.deltas.push(Delta {
+            index: event.index,
+            opcode,
+            written: event.written,
+            popped: match opcode {
+                InsnOpcode::Pop => event.reads.last().map(|(_, value)| *value),
+                _ => None,
+            },
+        });
+
+        Ok(())
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn deltas(&self) -> &[Delta] {
+        &self.deltas
+    }
+
+    // The step that last wrote operand, and what it did
+    pub fn last_write(&self, operand: InsnOperand) -> Option<(usize, &Delta)> {
+        self.deltas
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, delta)| delta.written.is_some_and(|written| written.operand == operand))
+    }
+
+    // Undoes the last step recorded on state, false when there's nothing to undo
+    pub fn step_back(&mut self, state: &mut State) -> bool {
+        match self.deltas.pop() {
+            None => false,
+            Some(delta) => {
+                state.undo(&delta);
+                true
+            }
+        }
+    }
+}
+
+impl State {
+    // Puts back what delta changed, leaving the state as it was before its instruction ran
+    fn undo(&mut self, delta: &Delta) {
+        match delta.written {
+            // a slot Push created
+            Some(Written { operand: InsnOperand::Stack(_), old: None, .. }) => {
+                self.stack.pop();
+            }
+            Some(Written { operand: InsnOperand::Stack(n), old: Some(old), .. }) => self.stack[n] = old,
+            Some(Written { operand: InsnOperand::Reg(n), old: Some(old), .. }) => self.regs[n as usize] = old,
+            _ => {}
+        }
+        if let Some(value) = delta.popped {
+            self.stack.push(value);
+        }
+
+        match delta.opcode {
+            InsnOpcode::Out => {
+                self.output.pop();
+            }
+            // a finished program can't have run anything after, so it wasn't finished before
+            InsnOpcode::Ret | InsnOpcode::Halt => {
+                self.returned = None;
+                self.halted = false;
+            }
+            _ => {}
+        }
+
+        self.i = delta.index;
+        self.steps -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+    use crate::helium::HELIUM;
+    use crate::vm::Status;
+
+    #[test]
+    fn back_and_forth() {
+        let program = "Ldc Reg3, 2\nPush Reg3\nMul Reg3, 5\nOut Reg3\nPop Reg3\nAdd Reg3, 1\nRet Reg3";
+        let mut state = State::new(&HELIUM, assemble(program).unwrap().instructions);
+        let mut history = History::new();
+        let before = state.snapshot(None).unwrap();
+
+        assert!(matches!(state.advance_traced(usize::MAX, &mut history), Status::Finished(_)));
+        let (step, delta) = history.last_write(InsnOperand::Reg(3)).unwrap();
+        assert_eq!((step, delta.index), (5, 5));
+        assert_eq!(delta.written.unwrap().old, Some(2));
+        assert_eq!(history.last_write(InsnOperand::Stack(0)).unwrap().1.index, 1);
+
+        // back to just after the Mul, then all the way
+        for _ in 0..4 {
+            assert!(history.step_back(&mut state));
+        }
+        assert_eq!((state.index(), state.steps()), (3, 3));
+        assert_eq!((state.regs[3], &state.stack, &state.output), (10, &vec![2], &vec![]));
+        while history.step_back(&mut state) {}
+        assert_eq!(state.snapshot(None).unwrap(), before);
+    }
+}
@@ -0,0 +1,161 @@
+// Execution profiles: a trace sink that counts how often each instruction and
+// each opcode ran, and optionally how long they took, with a report that
+// points hot instructions back at the source they came from
+//
+// Time is sampled between events, so an instruction is charged for itself and
+// the vm's bookkeeping around it; compare times with each other, not a clock.
+use crate::helium::{Instruction, OPCODES};
+use crate::vm::trace::{Event, Sink};
+use std::io;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    // runs per instruction index
+    counts: Vec<u64>,
+    // runs per opcode, in OPCODES order
+    opcodes: [u64; OPCODES.len()],
+    // time per instruction index, when timed
+    times: Option<Vec<Duration>>,
+    last: Option<Instant>,
+}
+
+impl Sink for Profile {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        if self.counts.len() <= event.index {
+            self.counts.resize(event.index + 1, 0);
+        }
+        self.counts[event.index] += 1;
+        let opcode = OPCODES.iter().position(|op| *op == event.instruction.opcode).unwrap();
+        self.opcodes[opcode] += 1;
+
+        if let Some(times) = &mut self.times {
+            let now = Instant::now();
+            if times.len() <= event.index {
+                times.resize(event.index + 1, Duration::ZERO);
+            }
+            times[event.index] += now - self.last.unwrap_or(now);
+            self.last = Some(now);
+        }
+
+        Ok(())
+    }
+}
+
+impl Profile {
+    // Counts only
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    // Counts and wall time, from now on
+    pub fn timed() -> Profile {
+        Profile {
+            times: Some(Vec::new()),
+            last: Some(Instant::now()),
+            ..Profile::default()
+        }
+    }
+
+    pub fn count(&self, index: usize) -> u64 {
+        self.counts.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn time(&self, index: usize) -> Option<Duration> {
+        self.times.as_ref().map(|times| times.get(index).copied().unwrap_or(Duration::ZERO))
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The report for a run of instructions, compiled from source if it's given.
+    // Instructions that never ran are left out
+    pub fn report(&self, instructions: &[Instruction], source: Option<&str>) -> String {
+        let total = self.total().max(1) as f64;
+        let share = |count: u64| format!("{:5.1}%", count as f64 * 100.0 / total);
+        let mut buff = String::new();
+
+        let mut hot: Vec<usize> = (0..self.counts.len()).filter(|index| self.counts[*index] > 0).collect();
+        hot.sort_by_key(|index| std::cmp::Reverse(self.counts[*index]));
+
+        buff.push_str("instructions, hottest first:\n");
+        for index in hot {
+            // counts for a different, longer program have nothing to name
+            let Some(insn) = instructions.get(index) else {
+                continue;
+            };
+            buff.push_str(&format!("{:>8} {}", self.counts[index], share(self.counts[index])));
+            if let Some(time) = self.time(index) {
+                buff.push_str(&format!(" {:>10.3?}", time));
+            }
+            buff.push_str(&format!("  {}: {}", index, insn));
+            // a span outside the source is left out rather than trusted
+            if let Some(code) = source.zip(insn.span).and_then(|(text, span)| text.get(span.start..span.end)) {
+                buff.push_str(&format!("  `{}`", code));
+            }
+            buff.push('\n');
+        }
+
+        buff.push_str("opcodes:\n");
+        for (opcode, count) in OPCODES.iter().zip(self.opcodes) {
+            if count > 0 {
+                buff.push_str(&format!("{:>8} {}  {:?}\n", count, share(count), opcode));
+            }
+        }
+
+        if let Some(text) = source {
+            // 1 based, like the lines errors point at
+            let mut lines: Vec<u64> = vec![0; text.matches('\n').count() + 1];
+            for (index, count) in self.counts.iter().enumerate() {
+                let span = instructions.get(index).and_then(|insn| insn.span);
+                if let Some(before) = span.and_then(|span| text.get(..span.start)) {
+                    lines[before.matches('\n').count()] += count;
+                }
+            }
+
+            buff.push_str("source lines:\n");
+            for ((line, count), code) in lines.iter().enumerate().zip(text.split('\n')) {
+                if *count > 0 {
+                    buff.push_str(&format!("{:>8} {}  {} | {}\n", count, share(*count), line + 1, code));
+                }
+            }
+        }
+
+        buff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::HELIUM;
+    use crate::vm::run_traced;
+    use crate::{compile, parse, tokenize};
+
+    #[test]
+    fn counts_and_report() {
+        let source = "1+2\n7*3-4";
+        let insns = compile(parse(tokenize(source.as_bytes()).unwrap()).unwrap()).unwrap();
+        let mut profile = Profile::new();
+        run_traced(&HELIUM, insns.clone(), &mut profile).unwrap();
+
+        assert_eq!(profile.total(), insns.len() as u64);
+        assert!((0..insns.len()).all(|index| profile.count(index) == 1));
+        assert_eq!(profile.time(0), None);
+
+        let report = profile.report(&insns, Some(source));
+        assert!(report.contains("       2  66.7%  Out\n"), "{}", report);
+        assert!(report.contains("2 | 7*3-4\n"), "{}", report);
+        // spans that don't fit some other source are left out
+        assert!(!profile.report(&insns, Some("1")).contains('`'));
+        // nor does a shorter program panic, its missing instructions are skipped
+        let short = profile.report(&insns[..2], Some(source));
+        assert!(!short.contains("  2: "), "{}", short);
+        assert!(short.contains("1 | 1+2\n"), "{}", short);
+
+        let mut timed = Profile::timed();
+        run_traced(&HELIUM, insns.clone(), &mut timed).unwrap();
+        assert!(timed.time(0).is_some());
+    }
+}
@@ -0,0 +1,245 @@
+// Snapshots of a running program (.hvs), to checkpoint a long computation or
+// hand a failing run to someone else to replay
+//
+// All integers are little endian.
+//
+//   magic      4 bytes  "HVS\0"
+//   version    u16      VERSION
+//   program    u32 length, then the program as a .hbc file, with the spans and
+//              the source they point into when there are any
+//   registers  u32 count, then count i32 values; the count is the target's
+//   stack      u32 count, then count i32 values, bottom first
+//   output     u32 count, then count i32 values
+//   i          u64      the instruction it runs next
+//   steps      u64      instructions run so far
+//   returned   u8 0, or 1 then the i32 Ret stopped with
+//   halted     u8       0 or 1
+//   fuel       u8 0, or 1 then the u64 limit
+//   max stack  u8 0, or 1 then the u64 limit
+//   checksum   u32      CRC-32 of every byte before it
+use crate::error::Error;
+use crate::helium::hbc::{self, checked, crc32, u32_of, Reader};
+use crate::helium::Target;
+use crate::vm::{Limits, State};
+use std::fs;
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"HVS\0";
+pub const VERSION: u16 = 1;
+
+fn put_len(buff: &mut Vec<u8>, len: usize, what: &str) -> Result<(), Error> {
+    let len = u32_of(len, what).map_err(|message| Error::Encode { message })?;
+    buff.extend_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+fn put_values(buff: &mut Vec<u8>, values: &[i32], what: &str) -> Result<(), Error> {
+    put_len(buff, values.len(), what)?;
+    for value in values {
+        buff.extend_from_slice(&value.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+fn values(r: &mut Reader, what: &str) -> Result<Vec<i32>, String> {
+    let count = r.u32(&format!("{} count", what))? as usize;
+    let mut values: Vec<i32> = Vec::new();
+    for _ in 0..count {
+        values.push(r.u32(what)? as i32);
+    }
+
+    Ok(values)
+}
+
+// A u8 flag, then the value if it's 1
+fn optional<T>(
+    r: &mut Reader,
+    what: &str,
+    value: impl FnOnce(&mut Reader) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    match r.u8(what)? {
+        0 => Ok(None),
+        1 => Ok(Some(value(r)?)),
+        flag => Err(format!("Invalid flag {} for {}", flag, what)),
+    }
+}
+
+impl State {
+    // source is what the program was compiled from, if its spans point anywhere
+    pub fn snapshot(&self, source: Option<&str>) -> Result<Vec<u8>, Error> {
+        let program = hbc::encode(&self.instructions, source)?;
+
+        let mut buff: Vec<u8> = Vec::new();
+        buff.extend_from_slice(MAGIC);
+        buff.extend_from_slice(&VERSION.to_le_bytes());
+        put_len(&mut buff, program.len(), "Program length")?;
+        buff.extend_from_slice(&program);
+        put_values(&mut buff, &self.regs, "Register count")?;
+        put_values(&mut buff, &self.stack, "Stack depth")?;
+        put_values(&mut buff, &self.output, "Output length")?;
+        buff.extend_from_slice(&(self.i as u64).to_le_bytes());
+        buff.extend_from_slice(&self.steps.to_le_bytes());
+        match self.returned {
+            None => buff.push(0),
+            Some(value) => {
+                buff.push(1);
+                buff.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        buff.push(self.halted as u8);
+        match self.limits.fuel {
+            None => buff.push(0),
+            Some(fuel) => {
+                buff.push(1);
+                buff.extend_from_slice(&fuel.to_le_bytes());
+            }
+        }
+        match self.limits.stack {
+            None => buff.push(0),
+            Some(stack) => {
+                buff.push(1);
+                buff.extend_from_slice(&(stack as u64).to_le_bytes());
+            }
+        }
+
+        let checksum = crc32(&buff);
+        buff.extend_from_slice(&checksum.to_le_bytes());
+        Ok(buff)
+    }
+
+    // The state a snapshot was taken of, ready to go on from where it was, and
+    // the source if the snapshot has one
+    pub fn restore(bytes: &[u8]) -> Result<(State, Option<String>), Error> {
+        read(bytes).map_err(|message| Error::Decode { message })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, source: Option<&str>) -> Result<(), Error> {
+        Ok(fs::write(path, self.snapshot(source)?)?)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<(State, Option<String>), Error> {
+        State::restore(&fs::read(path)?)
+    }
+}
+
+fn read(bytes: &[u8]) -> Result<(State, Option<String>), String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(String::from("Not a Helium vm snapshot: bad magic number"));
+    }
+    let bytes = checked(bytes)?;
+    let mut r = Reader { bytes, pos: 4 };
+
+    let version = r.u16("version")?;
+    if version != VERSION {
+        return Err(format!("Unsupported snapshot version {} (expected {})", version, VERSION));
+    }
+
+    let len = r.u32("program length")? as usize;
+    let (instructions, source) = match hbc::decode(r.take(len, "program")?) {
+        Err(err) => return Err(format!("Bad program in the snapshot: {}", err.message())),
+        Ok(program) => program,
+    };
+
+    let regs = values(&mut r, "register")?;
+    let target = Target { registers: regs.len() };
+    target.check().map_err(|err| err.message())?;
+    let mut state = State::new(&target, instructions);
+    state.regs = regs;
+    state.stack = values(&mut r, "stack slot")?;
+    state.output = values(&mut r, "output value")?;
+
+    let i = r.u64("instruction index")?;
+    state.i = match usize::try_from(i) {
+        Ok(i) if i <= state.instructions.len() => i,
+        _ => {
+            return Err(format!(
+                "Instruction index {} out of range ({} instructions)",
+                i,
+                state.instructions.len()
+            ))
+        }
+    };
+    state.steps = r.u64("step count")?;
+    state.returned = optional(&mut r, "returned value", |r| Ok(r.u32("returned value")? as i32))?;
+    state.halted = match r.u8("halted")? {
+        0 => false,
+        1 => true,
+        flag => return Err(format!("Invalid flag {} for halted", flag)),
+    };
+    state.limits = Limits {
+        fuel: optional(&mut r, "fuel", |r| r.u64("fuel"))?,
+        stack: optional(&mut r, "max stack", |r| {
+            let stack = r.u64("max stack")?;
+            usize::try_from(stack).map_err(|_| format!("Max stack {} doesn't fit in memory", stack))
+        })?,
+    };
+
+    if r.pos != bytes.len() {
+        return Err(format!("{} unexpected bytes before the checksum", bytes.len() - r.pos));
+    }
+
+    Ok((state, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::helium::hbc::crc32;
+    use crate::helium::HELIUM;
+    use crate::vm::snapshot::VERSION;
+    use crate::vm::{Limits, State, Status, Value};
+    use crate::{compile, compile_for, parse, tokenize, Target};
+
+    #[test]
+    fn round_trip() {
+        let target = Target { registers: 2 };
+        let source = "1+2*3*4*5\n6-7\n8/2";
+        let insns = compile_for(parse(tokenize(source.as_bytes()).unwrap()).unwrap(), &target).unwrap();
+        let mut state = State::new(&target, insns);
+        assert!(matches!(state.advance(2), Status::Running));
+
+        let bytes = state.snapshot(Some(source)).unwrap();
+        let (mut restored, text) = State::restore(&bytes).unwrap();
+        assert_eq!(text.as_deref(), Some(source));
+        assert_eq!(restored.snapshot(Some(source)).unwrap(), bytes);
+        assert_eq!(restored.instructions[0].span, state.instructions[0].span);
+        assert_eq!((restored.index(), restored.steps()), (2, 2));
+
+        let expected = Value::List(vec![121, -1, 4]);
+        assert!(matches!(restored.advance(usize::MAX), Status::Finished(value) if value == expected));
+        assert!(matches!(state.advance(usize::MAX), Status::Finished(value) if value == expected));
+
+        let mut corrupt = bytes.clone();
+        corrupt[bytes.len() - 10] ^= 1;
+        assert!(matches!(State::restore(&corrupt), Err(Error::Decode { .. })));
+        assert!(State::restore(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn wide_fields() {
+        let insns = compile(parse(tokenize("1+2".as_bytes()).unwrap()).unwrap()).unwrap();
+        let mut state = State::new(&HELIUM, insns);
+        let limits = Limits {
+            fuel: Some(u64::MAX),
+            stack: Some(u32::MAX as usize + 1),
+        };
+        state.set_limits(limits);
+
+        let bytes = state.snapshot(Some("1+2")).unwrap();
+        let (restored, _) = State::restore(&bytes).unwrap();
+        assert_eq!(restored.limits, limits);
+        // without the source the spans are left out
+        let (restored, source) = State::restore(&state.snapshot(None).unwrap()).unwrap();
+        assert_eq!((restored.instructions[0].span, source), (None, None));
+
+        // from some later version
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let end = newer.len() - 4;
+        let checksum = crc32(&newer[..end]);
+        newer[end..].copy_from_slice(&checksum.to_le_bytes());
+        let err = State::restore(&newer).map(|_| ()).unwrap_err();
+        assert_eq!(err.message(), "Unsupported snapshot version 2 (expected 1)");
+    }
+}
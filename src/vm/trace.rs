@@ -0,0 +1,174 @@
+// Execution traces: the vm hands a sink one event per instruction it runs,
+// with every location it read and the one it wrote
+//
+// Text traces are one line per step:
+//
+//   2: Add Reg0, Reg1 | Reg0 = 3, Reg1 = 4 | Reg0: 3 -> 7
+//
+// JSON Lines traces have one object per step:
+//
+//   {"index":2,"opcode":"Add","operands":["Reg0","Reg1"],"span":[0,3],
+//    "reads":[{"operand":"Reg0","value":3},{"operand":"Reg1","value":4}],
+//    "write":{"operand":"Reg0","old":3,"new":7}}
+//
+// (on a single line), with "span" and "write" null when there's none and
+// "old" null for a stack slot a push creates.
+use crate::helium::{InsnOperand, Instruction};
+use std::io;
+use std::io::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Written {
+    pub operand: InsnOperand,
+    // None when the location didn't exist before, like a slot Push creates
+    pub old: Option<i32>,
+    pub new: i32,
+}
+
+#[derive(Debug)]
+pub struct Event<'a> {
+    pub index: usize,
+    pub instruction: &'a Instruction,
+    // registers and stack slots, in the order they were read; immediates aren't
+    pub reads: &'a [(InsnOperand, i32)],
+    pub written: Option<Written>,
+}
+
+pub trait Sink {
+    fn event(&mut self, event: &Event) -> io::Result<()>;
+
+    // Sinks that throw events away say so, so the vm doesn't build them
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+pub struct NoTrace;
+
+impl Sink for NoTrace {
+    fn event(&mut self, _: &Event) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+pub struct TextTrace<W: Write>(pub W);
+
+impl<W: Write> Sink for TextTrace<W> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let reads: Vec<String> = event
+            .reads
+            .iter()
+            .map(|(operand, value)| format!("{} = {}", operand, value))
+            .collect();
+
+        write!(self.0, "{}: {}", event.index, event.instruction)?;
+        if !reads.is_empty() {
+            write!(self.0, " | {}", reads.join(", "))?;
+        }
+        match event.written {
+            None => writeln!(self.0),
+            Some(Written { operand, old: None, new }) => writeln!(self.0, " | {}: -> {}", operand, new),
+            Some(Written { operand, old: Some(old), new }) => {
+                writeln!(self.0, " | {}: {} -> {}", operand, old, new)
+            }
+        }
+    }
+}
+
+pub struct JsonTrace<W: Write>(pub W);
+
+// Only ever applied to opcode and operand names, but they're quoted properly anyway
+fn json_string(text: &str) -> String {
+    let mut buff = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => buff.push_str("\\\""),
+            '\\' => buff.push_str("\\\\"),
+            c if (c as u32) < 0x20 => buff.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buff.push(c),
+        }
+    }
+    buff.push('"');
+
+    buff
+}
+
+impl<W: Write> Sink for JsonTrace<W> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let operands: Vec<String> = event
+            .instruction
+            .operands
+            .iter()
+            .map(|operand| json_string(&operand.to_string()))
+            .collect();
+        let span = match event.instruction.span {
+            None => String::from("null"),
+            Some(span) => format!("[{},{}]", span.start, span.end),
+        };
+        let reads: Vec<String> = event
+            .reads
+            .iter()
+            .map(|(operand, value)| {
+                format!("{{\"operand\":{},\"value\":{}}}", json_string(&operand.to_string()), value)
+            })
+            .collect();
+        let write = match event.written {
+            None => String::from("null"),
+            Some(written) => format!(
+                "{{\"operand\":{},\"old\":{},\"new\":{}}}",
+                json_string(&written.operand.to_string()),
+                written.old.map_or(String::from("null"), |old| old.to_string()),
+                written.new
+            ),
+        };
+
+        writeln!(
+            self.0,
+            "{{\"index\":{},\"opcode\":{},\"operands\":[{}],\"span\":{},\"reads\":[{}],\"write\":{}}}",
+            event.index,
+            json_string(&format!("{:?}", event.instruction.opcode)),
+            operands.join(","),
+            span,
+            reads.join(","),
+            write
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::asm::assemble;
+    use crate::helium::HELIUM;
+    use crate::vm::run_traced;
+
+    #[test]
+    fn text_and_json() {
+        let program = "Ldc Reg0, 3\nPush Reg0\nPop Reg1\nAdd Reg0, Reg1\nRet Reg0";
+
+        let mut text = TextTrace(Vec::new());
+        run_traced(&HELIUM, assemble(program).unwrap().instructions, &mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text.0).unwrap(),
+            "0: Ldc Reg0, Imm(3) | Reg0: 0 -> 3\n\
+             1: Push Reg0 | Reg0 = 3 | Stack(0): -> 3\n\
+             2: Pop Reg1 | Stack(0) = 3 | Reg1: 0 -> 3\n\
+             3: Add Reg0, Reg1 | Reg0 = 3, Reg1 = 3 | Reg0: 3 -> 6\n\
+             4: Ret Reg0 | Reg0 = 6\n"
+        );
+
+        let mut json = JsonTrace(Vec::new());
+        run_traced(&HELIUM, assemble(program).unwrap().instructions, &mut json).unwrap();
+        let json = String::from_utf8(json.0).unwrap();
+        assert_eq!(
+            json.lines().nth(1).unwrap(),
+            "{\"index\":1,\"opcode\":\"Push\",\"operands\":[\"Reg0\"],\"span\":null,\
+             \"reads\":[{\"operand\":\"Reg0\",\"value\":3}],\"write\":{\"operand\":\"Stack(0)\",\"old\":null,\"new\":3}}"
+        );
+        assert_eq!(json.lines().count(), 5);
+    }
+}